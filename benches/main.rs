@@ -1,4 +1,6 @@
 use bcc::common::*;
+use bcc::engine::{Config, Engine};
+use bcc::store::Backend;
 use bcc::transaction::Transaction;
 use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
 use rand::{Rng, RngCore, SeedableRng};
@@ -22,14 +24,17 @@ fn gen_inputs(size: usize) -> Vec<Transaction> {
             5 => Transaction::Dispute {
                 client: rng.gen::<u16>(),
                 tx_id: rng.gen::<u32>() % (i + 1) as u32,
+                value: None,
             },
             6 => Transaction::Resolve {
                 client: rng.gen::<u16>(),
                 tx_id: rng.gen::<u32>() % (i + 1) as u32,
+                value: None,
             },
             7 => Transaction::Chargeback {
                 client: rng.gen::<u16>(),
                 tx_id: rng.gen::<u32>() % (i + 1) as u32,
+                value: None,
             },
             _ => unreachable!(),
         };
@@ -70,5 +75,28 @@ pub fn criterion_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, criterion_benchmark);
+// Compare the on disk transaction stores, on a single worker to leave parallelism out
+pub fn store_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Store");
+    let input_size = [10_000, 100_000, 1_000_000];
+    for i in input_size.iter() {
+        let input = gen_inputs(*i);
+        for (name, backend) in [("redb", Backend::Redb(None)), ("log", Backend::Log(None))] {
+            group.bench_with_input(BenchmarkId::new(name, i), i, |b, _| {
+                b.iter(|| {
+                    let config = Config {
+                        store: backend.clone(),
+                        ..Config::default()
+                    };
+                    Engine::with_config(1, config)
+                        .unwrap()
+                        .run(input.clone().into_iter())
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark, store_benchmark);
 criterion_main!(benches);
@@ -1,18 +1,30 @@
 use super::common::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Default, Clone, Copy, Serialize, Debug, PartialEq)]
+#[derive(Default, Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
 pub struct AccountInner<ST> {
     pub available: Value,
     pub held: Value,
+    /// Total fees charged to the account, already deducted from `available`
+    pub fees: Value,
+    #[serde(skip)]
     _marker: std::marker::PhantomData<ST>,
 }
 
-#[derive(Serialize, Debug, PartialEq)]
+/// An account in any of its states.
+///
+/// The engine only ever freezes accounts. Suspending, reviewing, closing, reinstating and
+/// approving are library-only transitions, for administrative tooling built on this crate:
+/// neither the input format nor the CLI reach them, other than through accounts restored
+/// from a checkpoint.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
 pub enum Account {
     Active(AccountInner<Active>),
+    Suspended(AccountInner<Suspended>),
+    UnderReview(AccountInner<UnderReview>),
     Frozen(AccountInner<Frozen>),
+    Closed(AccountInner<Closed>),
 }
 
 impl Default for Account {
@@ -21,15 +33,54 @@ impl Default for Account {
     }
 }
 
+/// Normal operation
 #[derive(Default, Debug, PartialEq, Clone, Copy)]
 pub struct Active;
+/// Deposits are still accepted, withdrawals are blocked
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Suspended;
+/// No client initiated operation is allowed until the review is concluded,
+/// disputes on past transactions are still processed
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct UnderReview;
+/// Result of a chargeback, no further operations are allowed
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Frozen;
+/// Terminal state, can only be reached with no funds left in the account
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Closed;
+
+/// States accepting deposits
+pub trait Deposits {}
+impl Deposits for Active {}
+impl Deposits for Suspended {}
+
+/// States in which disputes, resolutions and chargebacks are processed
+pub trait Disputes {}
+impl Disputes for Active {}
+impl Disputes for Suspended {}
+impl Disputes for UnderReview {}
 
 #[derive(Debug, Error)]
 pub enum AccountError {
     #[error("not enough funds")]
     NotEnoughFunds,
+    #[error("operation not allowed on {0} account")]
+    NotAllowed(&'static str),
+    #[error("account still holds funds")]
+    BalanceNotZero,
+}
+
+impl<ST> AccountInner<ST> {
+    // Only transitions implemented below are legal, keep this private.
+    fn transition<T>(&self) -> AccountInner<T> {
+        AccountInner {
+            _marker: std::marker::PhantomData::<T>,
+            held: self.held,
+            available: self.available,
+            fees: self.fees,
+        }
+    }
 }
 
 impl AccountInner<Active> {
@@ -44,6 +95,22 @@ impl AccountInner<Active> {
         })
     }
 
+    /// Withdraw `amount` and charge `fee` on top of it, both must be covered by available funds
+    pub fn withdraw_with_fee(&self, amount: Value, fee: Value) -> Result<Self, AccountError> {
+        // no account could cover more than the largest value
+        if amount.checked_add(fee).is_none_or(|total| self.available < total) {
+            return Err(AccountError::NotEnoughFunds);
+        }
+        Ok(self.withdraw(amount)?.charge_fee(fee))
+    }
+
+    /// Library-only, see [`Account`]
+    pub fn suspend(&self) -> AccountInner<Suspended> {
+        self.transition()
+    }
+}
+
+impl<ST: Deposits> AccountInner<ST> {
     pub fn deposit(&self, amount: Value) -> Result<Self, AccountError> {
         Ok(Self {
             available: self.available + amount,
@@ -51,6 +118,21 @@ impl AccountInner<Active> {
         })
     }
 
+    /// Library-only, see [`Account`]
+    pub fn review(&self) -> AccountInner<UnderReview> {
+        self.transition()
+    }
+
+    /// Library-only, see [`Account`]
+    pub fn close(&self) -> Result<AccountInner<Closed>, AccountError> {
+        if !self.available.is_zero() || !self.held.is_zero() {
+            return Err(AccountError::BalanceNotZero);
+        }
+        Ok(self.transition())
+    }
+}
+
+impl<ST: Disputes> AccountInner<ST> {
     pub fn freeze_funds(&self, amount: Value) -> Result<Self, AccountError> {
         // It could happen that the client has already spent funds which are now disputed.
         // In such cases, assume the balance can go negative to reflect a debit with the bank.
@@ -82,18 +164,127 @@ impl AccountInner<Active> {
         })
     }
 
+    pub fn charge_fee(&self, fee: Value) -> Self {
+        // Like disputes, fees may be due on funds already spent, let the balance go negative.
+        Self {
+            available: self.available - fee,
+            fees: self.fees + fee,
+            ..*self
+        }
+    }
+
     pub fn freeze(&self) -> AccountInner<Frozen> {
-        AccountInner {
-            _marker: std::marker::PhantomData::<Frozen>,
-            held: self.held,
-            available: self.available,
+        self.transition()
+    }
+}
+
+impl AccountInner<Frozen> {
+    /// Credit back `amount` which was charged back
+    pub fn represent(&self, amount: Value) -> Self {
+        Self {
+            available: self.available + amount,
+            ..*self
         }
     }
 }
 
-impl From<AccountInner<Frozen>> for Account {
-    fn from(from: AccountInner<Frozen>) -> Self {
-        Self::Frozen(from)
+impl AccountInner<Suspended> {
+    /// Library-only, see [`Account`]
+    pub fn reinstate(&self) -> AccountInner<Active> {
+        self.transition()
+    }
+}
+
+impl AccountInner<UnderReview> {
+    /// Library-only, see [`Account`]
+    pub fn approve(&self) -> AccountInner<Active> {
+        self.transition()
+    }
+}
+
+// Apply the same operation to every variant whose state supports it, the compiler checks
+// the operation is legal for each listed state.
+macro_rules! dispatch {
+    ($account:expr, $inner:ident => $op:expr, $($state:ident)|+) => {
+        match $account {
+            $(Account::$state($inner) => Ok(Account::from($op)),)+
+            other => Err(AccountError::NotAllowed(other.state())),
+        }
+    };
+}
+
+impl Account {
+    /// Human readable name of the account state
+    pub fn state(&self) -> &'static str {
+        match self {
+            Account::Active(_) => "active",
+            Account::Suspended(_) => "suspended",
+            Account::UnderReview(_) => "under_review",
+            Account::Frozen(_) => "frozen",
+            Account::Closed(_) => "closed",
+        }
+    }
+
+    /// Whether the account has been locked for good
+    pub fn is_locked(&self) -> bool {
+        matches!(self, Account::Frozen(_) | Account::Closed(_))
+    }
+
+    pub fn available(&self) -> Value {
+        self.balances().0
+    }
+
+    pub fn held(&self) -> Value {
+        self.balances().1
+    }
+
+    pub fn fees(&self) -> Value {
+        self.balances().2
+    }
+
+    fn balances(&self) -> (Value, Value, Value) {
+        match self {
+            Account::Active(i) => (i.available, i.held, i.fees),
+            Account::Suspended(i) => (i.available, i.held, i.fees),
+            Account::UnderReview(i) => (i.available, i.held, i.fees),
+            Account::Frozen(i) => (i.available, i.held, i.fees),
+            Account::Closed(i) => (i.available, i.held, i.fees),
+        }
+    }
+
+    pub fn deposit(&self, amount: Value) -> Result<Account, AccountError> {
+        dispatch!(self, inner => inner.deposit(amount)?, Active | Suspended)
+    }
+
+    pub fn withdraw_with_fee(&self, amount: Value, fee: Value) -> Result<Account, AccountError> {
+        dispatch!(self, inner => inner.withdraw_with_fee(amount, fee)?, Active)
+    }
+
+    pub fn freeze_funds(&self, amount: Value) -> Result<Account, AccountError> {
+        dispatch!(self, inner => inner.freeze_funds(amount)?, Active | Suspended | UnderReview)
+    }
+
+    pub fn release_funds(&self, amount: Value) -> Result<Account, AccountError> {
+        dispatch!(self, inner => inner.release_funds(amount)?, Active | Suspended | UnderReview)
+    }
+
+    /// Freeze the account without touching its funds, e.g. on suspicion of fraud
+    pub fn freeze(&self) -> Result<Account, AccountError> {
+        dispatch!(self, inner => inner.freeze(), Active | Suspended | UnderReview)
+    }
+
+    /// Credit back `amount` to an account frozen by its chargeback, which stays frozen
+    pub fn represent(&self, amount: Value) -> Result<Account, AccountError> {
+        dispatch!(self, inner => inner.represent(amount), Frozen)
+    }
+
+    /// Charge back `amount`, charge `fee` and freeze the account
+    pub fn chargeback(&self, amount: Value, fee: Value) -> Result<Account, AccountError> {
+        dispatch!(
+            self,
+            inner => inner.chargeback(amount)?.charge_fee(fee).freeze(),
+            Active | Suspended | UnderReview
+        )
     }
 }
 
@@ -103,25 +294,33 @@ impl From<AccountInner<Active>> for Account {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl From<AccountInner<Suspended>> for Account {
+    fn from(from: AccountInner<Suspended>) -> Self {
+        Self::Suspended(from)
+    }
+}
 
-    impl Account {
-        pub fn available(&self) -> Value {
-            match self {
-                Account::Active(i) => i.available,
-                Account::Frozen(i) => i.available,
-            }
-        }
+impl From<AccountInner<UnderReview>> for Account {
+    fn from(from: AccountInner<UnderReview>) -> Self {
+        Self::UnderReview(from)
+    }
+}
 
-        pub fn held(&self) -> Value {
-            match self {
-                Account::Active(i) => i.held,
-                Account::Frozen(i) => i.held,
-            }
-        }
+impl From<AccountInner<Frozen>> for Account {
+    fn from(from: AccountInner<Frozen>) -> Self {
+        Self::Frozen(from)
     }
+}
+
+impl From<AccountInner<Closed>> for Account {
+    fn from(from: AccountInner<Closed>) -> Self {
+        Self::Closed(from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
     fn test_withdraw_no_balance() {
@@ -135,9 +334,58 @@ mod tests {
         assert!(account.release_funds(Value::ONE).is_err());
     }
 
+    #[test]
+    fn test_withdraw_fee_no_funds() {
+        let account = AccountInner::<Active>::default().deposit(Value::TEN).unwrap();
+        assert!(account.withdraw_with_fee(Value::TEN, Value::ONE).is_err());
+        let account = account.withdraw_with_fee(Value::TWO, Value::ONE).unwrap();
+        assert_eq!(account.available, Value::new(7, 0));
+        assert_eq!(account.fees, Value::ONE);
+        let account = AccountInner::<Active>::default().deposit(Value::MAX).unwrap();
+        assert!(account.withdraw_with_fee(Value::MAX, Value::ONE).is_err());
+    }
+
     #[test]
     fn test_chargeback_no_funds() {
         let account = AccountInner::<Active>::default();
         assert!(account.chargeback(Value::ONE).is_err());
     }
+
+    #[test]
+    fn test_suspended() {
+        let account: Account = AccountInner::<Active>::default().suspend().into();
+        let account = account.deposit(Value::TEN).unwrap();
+        assert_eq!(account.available(), Value::TEN);
+        assert!(matches!(
+            account.withdraw_with_fee(Value::ONE, Value::ZERO),
+            Err(AccountError::NotAllowed("suspended"))
+        ));
+        assert!(account.freeze_funds(Value::ONE).is_ok());
+    }
+
+    #[test]
+    fn test_under_review() {
+        let account: Account = AccountInner::<Active>::default()
+            .deposit(Value::TEN)
+            .unwrap()
+            .review()
+            .into();
+        assert!(account.deposit(Value::ONE).is_err());
+        assert!(account.withdraw_with_fee(Value::ONE, Value::ZERO).is_err());
+        let account = account.freeze_funds(Value::ONE).unwrap();
+        assert!(matches!(
+            account.chargeback(Value::ONE, Value::ZERO).unwrap(),
+            Account::Frozen(_)
+        ));
+    }
+
+    #[test]
+    fn test_close() {
+        let account = AccountInner::<Active>::default().deposit(Value::ONE).unwrap();
+        assert!(account.close().is_err());
+        let account: Account = account.withdraw(Value::ONE).unwrap().close().unwrap().into();
+        assert!(account.is_locked());
+        assert!(account.deposit(Value::ONE).is_err());
+        assert!(account.freeze_funds(Value::ONE).is_err());
+    }
 }
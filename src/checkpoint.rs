@@ -0,0 +1,115 @@
+use super::{account::Account, common::*, engine::Accounts};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use thiserror::Error;
+
+/// Accounts state after applying the first `last_row` transactions of an input,
+/// so that processing can be resumed after an interruption.
+///
+/// Saved as TOML:
+/// ```toml
+/// last_row = 2
+/// workers = 4
+///
+/// [[accounts]]
+/// client = 1
+///
+/// [accounts.account.Active]
+/// available = "1.5"
+/// held = "0"
+/// fees = "0"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Checkpoint {
+    /// Rows of the input fully applied, not counting the header
+    pub last_row: u64,
+    /// Workers of the run which saved it. Clients are sharded by the amount of workers, so
+    /// their stores are only found resuming with as many. Missing from older checkpoints
+    #[serde(default)]
+    pub workers: Option<usize>,
+    pub accounts: Vec<ClientAccount>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAccount {
+    pub client: Client,
+    pub account: Account,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error accessing checkpoint")]
+    Io(#[from] std::io::Error),
+    #[error("invalid checkpoint: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("cannot encode checkpoint: {0}")]
+    Encode(#[from] toml::ser::Error),
+}
+
+impl Checkpoint {
+    pub fn new(last_row: u64, workers: usize, accounts: &Accounts) -> Self {
+        let mut accounts = accounts
+            .iter()
+            .map(|(&client, &account)| ClientAccount { client, account })
+            .collect::<Vec<_>>();
+        accounts.sort_by_key(|entry| entry.client);
+        Self {
+            last_row,
+            workers: Some(workers),
+            accounts,
+        }
+    }
+
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn accounts(&self) -> Accounts {
+        self.accounts
+            .iter()
+            .map(|entry| (entry.client, entry.account))
+            .collect()
+    }
+
+    /// Replace the checkpoint at `path`, a write interrupted midway leaves the previous one intact
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, toml::to_string(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let frozen = Account::default()
+            .deposit(Value::TEN)
+            .and_then(|account| account.freeze_funds(Value::ONE))
+            .and_then(|account| account.chargeback(Value::ONE, Value::new(5, 1)))
+            .unwrap();
+        let accounts = Accounts::from([
+            (2, frozen),
+            (1, Account::default().deposit(Value::new(15, 1)).unwrap()),
+        ]);
+        let checkpoint = Checkpoint::new(3, 2, &accounts);
+        assert_eq!(checkpoint.accounts[0].client, 1);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checkpoint.toml");
+        checkpoint.write_to(&path).unwrap();
+        let read = Checkpoint::from_path(&path).unwrap();
+        assert_eq!(read, checkpoint);
+        assert_eq!(read.accounts(), accounts);
+        assert!(read.accounts()[&2].is_locked());
+
+        let older: Checkpoint = toml::from_str("last_row = 1\naccounts = []").unwrap();
+        assert_eq!(older.workers, None);
+    }
+}
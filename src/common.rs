@@ -1,3 +1,5 @@
 pub type Value = rust_decimal::Decimal;
+/// Amounts have up to this many decimal digits
+pub const SCALE: u32 = 4;
 pub type Client = u16;
 pub type TxId = u32;
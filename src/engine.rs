@@ -1,37 +1,280 @@
 use super::{
-    account::{self, Account, AccountInner, Active},
+    account::{self, Account},
+    checkpoint::Checkpoint,
     common::*,
-    store::{self, TransactionStore, TxRecord},
+    fee::FeeSchedule,
+    fraud::{Action, Activity, FraudRules, Screening},
+    invariant::{self, Ledger, Violation},
+    ledger::{Journal, LedgerAccount, Posting},
+    metrics::{Metrics, TimedStore, WorkerMetrics},
+    observer::{EngineObserver, Observers},
+    report::{Aggregates, ClientStats},
+    store::{self, Backend, Stats, TxRecord, TxStore},
     transaction::{
         Transaction::{self, *},
-        TxStatus,
+        TxKind, TxStatus,
     },
 };
 use std::thread::JoinHandle;
+use std::time::Instant;
 use std::{
     collections::HashMap,
-    sync::mpsc::{self, Receiver, SyncSender},
+    sync::mpsc::{self, Receiver, Sender, SyncSender},
+    sync::Arc,
 };
 use thiserror::Error;
 
-const BUF_SIZE: usize = 100;
+const DEFAULT_CHANNEL_CAPACITY: usize = 100;
+// Max transactions whose store writes are committed together
+const BATCH_SIZE: usize = 100;
 
 pub struct Engine {
     workers: Vec<WorkerHandle>,
+    sharding: Sharding,
+    // Position in the input of the next transaction
+    seq: u64,
+    metrics: Metrics,
+}
+
+/// Engine settings shared by all workers
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Fees charged on withdrawals and chargebacks
+    pub fees: FeeSchedule,
+    /// Where workers keep their transactions, each worker opens its own store
+    pub store: Backend,
+    /// Max transactions of the input between a deposit and its dispute, unlimited if `None`
+    pub dispute_window: Option<u64>,
+    /// Max transactions of the input between a chargeback and its representment,
+    /// unlimited if `None`
+    pub representment_window: Option<u64>,
+    pub negative_balance: NegativeBalance,
+    pub redispute: Redispute,
+    /// Transactions matching any of these are flagged, rejected or frozen
+    pub fraud: FraudRules,
+    /// Notified of what workers do
+    pub observers: Observers,
+    /// Check ledger invariants after every applied transaction, a violation fails its worker.
+    /// Balances of the [`Journal`] are kept to be checked too, not its postings.
+    pub check_invariants: bool,
+    /// Keep a [`Journal`] of the postings of every applied transaction, in memory until the
+    /// engine is done. Balances restored from a checkpoint predate it and are not booked.
+    pub ledger: bool,
+}
+
+/// Whether disputes can take the available balance of an account below zero
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NegativeBalance {
+    /// Funds already spent are held anyway, reflecting a debit with the bank
+    #[default]
+    Allow,
+    /// Disputes of more than the available funds are rejected
+    Reject,
+}
+
+/// Whether funds released by resolved disputes can be disputed again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Redispute {
+    /// Only what was never disputed can be
+    #[default]
+    Reject,
+    /// Resolved funds can be disputed again, e.g. on a new claim of the cardholder
+    Allow,
+}
+
+/// Picks the worker in charge of a client given the amount of workers.
+/// All transactions of a client must go to the same worker.
+pub type ShardFn = Arc<dyn Fn(Client, usize) -> usize + Send + Sync>;
+
+/// How clients are spread across workers
+#[derive(Clone, Default)]
+pub enum Sharding {
+    /// Client id modulo the amount of workers
+    #[default]
+    Modulo,
+    Custom(ShardFn),
+}
+
+impl std::fmt::Debug for Sharding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Modulo => f.write_str("Modulo"),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl Sharding {
+    fn worker(&self, client: Client, n_workers: usize) -> usize {
+        match self {
+            Self::Modulo => client as usize % n_workers,
+            // out of range workers would only be a panic waiting to happen
+            Self::Custom(shard) => shard(client, n_workers) % n_workers,
+        }
+    }
+}
+
+/// Builds an [`Engine`], anything not set is left to its default:
+/// ```
+/// # use bcc::engine::{EngineBuilder, NegativeBalance};
+/// # use bcc::store::Backend;
+/// let engine = EngineBuilder::new()
+///     .workers(2)
+///     .channel_capacity(1000)
+///     .store(Backend::Memory)
+///     .dispute_window(10_000)
+///     .negative_balance(NegativeBalance::Reject)
+///     .build()
+///     .unwrap();
+/// # engine.finish().unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct EngineBuilder {
+    workers: usize,
+    channel_capacity: usize,
+    sharding: Sharding,
+    config: Config,
+}
+
+impl Default for EngineBuilder {
+    fn default() -> Self {
+        Self {
+            workers: num_cpus::get(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            sharding: Sharding::default(),
+            config: Config::default(),
+        }
+    }
+}
+
+impl EngineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Amount of worker threads, one per CPU by default, at least one
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Transactions queued per worker before `Engine::feed` blocks, at least one
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.channel_capacity = capacity;
+        self
+    }
+
+    pub fn sharding(mut self, sharding: Sharding) -> Self {
+        self.sharding = sharding;
+        self
+    }
+
+    /// Replace all the settings shared by workers at once
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn fees(mut self, fees: FeeSchedule) -> Self {
+        self.config.fees = fees;
+        self
+    }
+
+    pub fn store(mut self, store: Backend) -> Self {
+        self.config.store = store;
+        self
+    }
+
+    /// Reject disputes coming more than `transactions` after the deposit in the input
+    pub fn dispute_window(mut self, transactions: u64) -> Self {
+        self.config.dispute_window = Some(transactions);
+        self
+    }
+
+    pub fn representment_window(mut self, transactions: u64) -> Self {
+        self.config.representment_window = Some(transactions);
+        self
+    }
+
+    pub fn negative_balance(mut self, policy: NegativeBalance) -> Self {
+        self.config.negative_balance = policy;
+        self
+    }
+
+    pub fn redispute(mut self, policy: Redispute) -> Self {
+        self.config.redispute = policy;
+        self
+    }
+
+    pub fn fraud_rules(mut self, rules: FraudRules) -> Self {
+        self.config.fraud = rules;
+        self
+    }
+
+    /// See [`Config::check_invariants`], this slows workers down quite a bit
+    pub fn check_invariants(mut self, check: bool) -> Self {
+        self.config.check_invariants = check;
+        self
+    }
+
+    /// See [`Config::ledger`], postings are kept in memory until the engine is done
+    pub fn ledger(mut self, ledger: bool) -> Self {
+        self.config.ledger = ledger;
+        self
+    }
+
+    /// Add an observer, called after those added before it
+    pub fn observer<O: EngineObserver + 'static>(mut self, observer: O) -> Self {
+        self.config.observers.push(Arc::new(observer));
+        self
+    }
+
+    pub fn build(self) -> Result<Engine, Error> {
+        if self.workers == 0 {
+            return Err(Error::Config("at least one worker is needed"));
+        }
+        if self.channel_capacity == 0 {
+            return Err(Error::Config("channel capacity must be at least one"));
+        }
+        let mut metrics = Vec::with_capacity(self.workers);
+        let workers = (0..self.workers)
+            .map(|id| {
+                let (worker, tx) = Worker::new(id, &self.config, self.channel_capacity)?;
+                metrics.push(worker.metrics.clone());
+                let handle = worker.run();
+                Ok::<_, Error>(WorkerHandle {
+                    tx,
+                    thread: Some(handle),
+                    failure: None,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Engine {
+            workers,
+            sharding: self.sharding,
+            seq: 0,
+            metrics: Metrics::new(metrics, self.channel_capacity),
+        })
+    }
 }
 
 impl Engine {
     /// Construct a new engine to process transactions
     /// n_workers constrols the amount of parallelism it will try to exploit
     pub fn new(n_workers: usize) -> Result<Self, Error> {
-        let workers = (0..n_workers)
-            .map(|_| {
-                let (worker, tx) = Worker::new()?;
-                let handle = worker.run();
-                Ok::<_, Error>(WorkerHandle { tx, handle })
-            })
-            .collect::<Result<Vec<_>, _>>()?;
-        Ok(Self { workers })
+        EngineBuilder::new().workers(n_workers).build()
+    }
+
+    /// Construct a new engine with custom settings
+    pub fn with_config(n_workers: usize, config: Config) -> Result<Self, Error> {
+        EngineBuilder::new()
+            .workers(n_workers)
+            .config(config)
+            .build()
+    }
+
+    pub fn builder() -> EngineBuilder {
+        EngineBuilder::new()
     }
 
     /// Process one transaction a' la sans I/O
@@ -46,20 +289,173 @@ impl Engine {
     /// purpose, this is the place to add the functionality (e.g. by saving timestamps / counters for each transaction).
     pub fn feed(&mut self, tx: Transaction) -> Result<(), Error> {
         // we could also use a more "fair" sharding strategy like id hashing, this is a starting point
-        let worker_id = tx.client() as usize % self.workers.len();
-        Ok(self.workers[worker_id].tx.send(tx)?)
+        let worker_id = self.sharding.worker(tx.client(), self.workers.len());
+        tracing::trace!(
+            seq = self.seq,
+            worker = worker_id,
+            client = tx.client(),
+            tx_id = tx.tx_id(),
+            "feeding transaction"
+        );
+        self.seq += 1;
+        let metrics = self.metrics.worker(worker_id);
+        // counted before sending, the worker may pick the transaction up right away
+        metrics.enqueued();
+        if self.workers[worker_id]
+            .tx
+            .send(Msg::Tx(self.seq - 1, tx))
+            .is_err()
+        {
+            metrics.dequeued();
+            return Err(self.failure(worker_id));
+        }
+        Ok(())
+    }
+
+    /// Amount of transactions fed so far, including those a checkpoint was resumed from
+    pub fn position(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Continue from a checkpoint, the following transactions are fed on top of its accounts.
+    ///
+    /// This is only meaningful before feeding any transaction. Disputes on transactions applied
+    /// before the checkpoint can only be processed if the store they were persisted in is reused.
+    pub fn resume(&mut self, checkpoint: &Checkpoint) -> Result<(), Error> {
+        if let Some(saved) = checkpoint
+            .workers
+            .filter(|&saved| saved != self.workers.len())
+        {
+            return Err(Error::WorkersChanged {
+                saved,
+                workers: self.workers.len(),
+            });
+        }
+        let mut shards = vec![Accounts::default(); self.workers.len()];
+        for (client, account) in checkpoint.accounts() {
+            shards[self.sharding.worker(client, self.workers.len())].insert(client, account);
+        }
+        for (worker, accounts) in shards.into_iter().enumerate() {
+            if self.workers[worker]
+                .tx
+                .send(Msg::Restore(accounts))
+                .is_err()
+            {
+                return Err(self.failure(worker));
+            }
+        }
+        self.seq = checkpoint.last_row;
+        Ok(())
+    }
+
+    /// Metrics of all workers, updated as they go
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Compact the transaction store of every worker.
+    ///
+    /// Workers compact their store once done with the transactions fed so far, so this is
+    /// best called between batches of transactions.
+    pub fn compact(&mut self) -> Result<(), Error> {
+        for (worker, reply) in self.broadcast(Msg::Compact)?.into_iter().enumerate() {
+            reply.recv().map_err(|_| self.failure(worker))??;
+        }
+        Ok(())
+    }
+
+    /// Aggregated statistics of all the transaction stores
+    pub fn stats(&mut self) -> Result<Stats, Error> {
+        let mut stats = Stats::default();
+        for (worker, reply) in self.broadcast(Msg::Stats)?.into_iter().enumerate() {
+            stats.merge(reply.recv().map_err(|_| self.failure(worker))??);
+        }
+        Ok(stats)
+    }
+
+    // Send a request to all workers and return the channels to collect their replies from
+    fn broadcast<T>(
+        &mut self,
+        msg: fn(Sender<Result<T, store::Error>>) -> Msg,
+    ) -> Result<Vec<Receiver<Result<T, store::Error>>>, Error> {
+        let mut replies = Vec::with_capacity(self.workers.len());
+        for worker in 0..self.workers.len() {
+            let (tx, rx) = mpsc::channel();
+            if self.workers[worker].tx.send(msg(tx)).is_err() {
+                return Err(self.failure(worker));
+            }
+            replies.push(rx);
+        }
+        Ok(replies)
+    }
+
+    // A worker only stops listening to requests if it failed or panicked, find out why
+    fn failure(&mut self, worker: usize) -> Error {
+        let handle = &mut self.workers[worker];
+        if let Some(thread) = handle.thread.take() {
+            handle.failure = Some(match thread.join() {
+                Ok(Ok(_)) => "stopped unexpectedly".to_string(),
+                Ok(Err(e)) => e.to_string(),
+                Err(panic) => panic_message(panic),
+            });
+        }
+        Error::WorkerFailed {
+            worker,
+            cause: handle.failure.clone().unwrap_or_default(),
+        }
     }
 
     /// Wait for all transactions to be processed
     pub fn finish(self) -> Result<Accounts, Error> {
-        Ok(self
-            .workers
-            .into_iter()
-            .flat_map(|WorkerHandle { tx, handle }| {
-                drop(tx);
-                handle.join().unwrap().accounts.into_iter()
-            })
-            .collect())
+        Ok(self.finish_with_stats()?.0)
+    }
+
+    /// Wait for all transactions to be processed, along with the activity of each client
+    pub fn finish_with_stats(self) -> Result<(Accounts, Aggregates), Error> {
+        let mut finished = self.finish_partial();
+        match finished.failures.is_empty() {
+            true => Ok((finished.accounts, finished.stats)),
+            false => Err(finished.failures.swap_remove(0)),
+        }
+    }
+
+    /// Wait for all transactions to be processed, even if some workers failed.
+    ///
+    /// Returns the state of the workers which did not fail, along with the failures.
+    /// Accounts of a worker whose store could not be flushed are kept, their transactions
+    /// may not have been persisted though.
+    pub fn finish_partial(self) -> Finished {
+        let mut finished = Finished::default();
+        for (worker, handle) in self.workers.into_iter().enumerate() {
+            drop(handle.tx);
+            let joined = match handle.thread {
+                Some(thread) => thread
+                    .join()
+                    .map_err(panic_message)
+                    .and_then(|state| state.map_err(|e| e.to_string())),
+                None => Err(handle.failure.unwrap_or_default()),
+            };
+            match joined {
+                Ok(mut state) => {
+                    if let Err(e) = state.txs.flush() {
+                        finished.failures.push(e.into());
+                    }
+                    finished.accounts.extend(state.accounts);
+                    finished.stats.extend(state.stats);
+                    if let Some(journal) = state.journal {
+                        finished.journal.merge(journal);
+                    }
+                }
+                Err(cause) => finished
+                    .failures
+                    .push(Error::WorkerFailed { worker, cause }),
+            }
+        }
+        finished
     }
 
     /// Run the engine on the incoming stream of transactions.
@@ -75,15 +471,72 @@ impl Engine {
     }
 }
 
+/// What is left of an engine once done
+#[derive(Debug, Default)]
+pub struct Finished {
+    /// Accounts of the workers which did not fail
+    pub accounts: Accounts,
+    /// Activity of the clients of those workers
+    pub stats: Aggregates,
+    /// Postings of those workers in input order, if the ledger was kept
+    pub journal: Journal,
+    pub failures: Vec<Error>,
+}
+
 struct WorkerHandle {
-    tx: SyncSender<Transaction>,
-    handle: JoinHandle<State>,
+    tx: SyncSender<Msg>,
+    // Taken once joined to find out why the worker failed
+    thread: Option<JoinHandle<Result<State, Error>>>,
+    failure: Option<String>,
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "panicked".to_string(),
+        },
+    }
+}
+
+// Requests to workers, processed in the order they are sent
+enum Msg {
+    // A transaction and its position in the input
+    Tx(u64, Transaction),
+    Compact(Sender<Result<bool, store::Error>>),
+    Stats(Sender<Result<Stats, store::Error>>),
+    // Accounts to start from, sent before any transaction
+    Restore(Accounts),
 }
 
 // Shard work based on account id, assuming transactions are independent
 struct Worker {
-    rx: Receiver<Transaction>,
+    id: usize,
+    rx: Receiver<Msg>,
     state: State,
+    metrics: Arc<WorkerMetrics>,
+    observers: Observers,
+    // Fraud rules flagging the transaction being processed, for observers
+    flagged: Vec<&'static str>,
+}
+
+// How a dispute is closed
+#[derive(Debug, Clone, Copy)]
+enum Closing {
+    // the held funds go back to the client
+    Resolve,
+    // the held funds are taken from the client, which is frozen
+    Chargeback,
+}
+
+// What a transaction did, reported to observers once its batch is committed
+struct Outcome {
+    tx: Transaction,
+    flagged: Vec<&'static str>,
+    before: Option<Account>,
+    after: Option<Account>,
+    result: Result<(), Error>,
 }
 
 #[derive(Error, Debug)]
@@ -91,32 +544,151 @@ pub enum Error {
     #[error(transparent)]
     Store(#[from] store::Error),
     #[error("something went wrong internally")]
-    Mpsc(#[from] mpsc::SendError<Transaction>),
+    Mpsc,
+    #[error("invalid configuration: {0}")]
+    Config(&'static str),
+    #[error("checkpoint saved with {saved} workers, cannot resume with {workers}")]
+    WorkersChanged { saved: usize, workers: usize },
+    #[error(transparent)]
+    InvariantViolated(#[from] Violation),
+    #[error("worker {worker} failed: {cause}")]
+    WorkerFailed { worker: usize, cause: String },
     #[error("account not found")]
     AccountNotFound,
     #[error("account frozen")]
     AccountFrozen,
+    #[error("account closed")]
+    AccountClosed,
     #[error(transparent)]
     Account(#[from] account::AccountError),
     #[error("transaction not available for dispute")]
     NotAvailableForDispute,
     #[error("transaction not in dispute")]
     NoDisputeActive,
+    #[error("transaction already resolved")]
+    AlreadyResolved,
+    #[error("transaction already charged back")]
+    AlreadyChargedBack,
+    #[error("amount not available for this transaction")]
+    InvalidAmount,
+    #[error("fee too large to be charged")]
+    FeeOverflow,
+    #[error("transaction too old to be disputed")]
+    DisputeWindowExpired,
+    #[error("transaction not charged back")]
+    NotChargedBack,
+    #[error("chargeback too old to be re-presented")]
+    RepresentmentWindowExpired,
+    #[error("rejected by fraud rule {0}")]
+    Fraud(&'static str),
+}
+
+impl Error {
+    /// Short name of the error, e.g. to label metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Store(store::Error::NotFound) => "transaction_not_found",
+            Self::Store(_) => "store",
+            Self::Mpsc => "internal",
+            Self::Config(_) => "config",
+            Self::WorkersChanged { .. } => "workers_changed",
+            Self::WorkerFailed { .. } => "worker_failed",
+            Self::AccountNotFound => "account_not_found",
+            Self::AccountFrozen => "account_frozen",
+            Self::AccountClosed => "account_closed",
+            Self::Account(account::AccountError::NotEnoughFunds) => "not_enough_funds",
+            Self::Account(account::AccountError::NotAllowed(_)) => "not_allowed",
+            Self::Account(account::AccountError::BalanceNotZero) => "balance_not_zero",
+            Self::NotAvailableForDispute => "not_available_for_dispute",
+            Self::NoDisputeActive => "no_dispute_active",
+            Self::AlreadyResolved => "already_resolved",
+            Self::AlreadyChargedBack => "already_charged_back",
+            Self::InvalidAmount => "invalid_amount",
+            Self::FeeOverflow => "fee_overflow",
+            Self::DisputeWindowExpired => "dispute_window_expired",
+            Self::NotChargedBack => "not_charged_back",
+            Self::RepresentmentWindowExpired => "representment_window_expired",
+            Self::Fraud(_) => "fraud",
+            Self::InvariantViolated(_) => "invariant_violated",
+        }
+    }
+}
+
+impl<T> From<mpsc::SendError<T>> for Error {
+    fn from(_: mpsc::SendError<T>) -> Self {
+        Self::Mpsc
+    }
+}
+
+impl From<mpsc::RecvError> for Error {
+    fn from(_: mpsc::RecvError) -> Self {
+        Self::Mpsc
+    }
 }
 
 impl Worker {
-    pub fn new() -> Result<(Self, SyncSender<Transaction>), Error> {
-        let (tx, rx) = std::sync::mpsc::sync_channel(BUF_SIZE);
+    pub fn new(
+        id: usize,
+        config: &Config,
+        capacity: usize,
+    ) -> Result<(Self, SyncSender<Msg>), Error> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(capacity);
+        let metrics = Arc::new(WorkerMetrics::default());
+        let txs = Box::new(TimedStore::new(config.store.open(id)?, metrics.clone()));
         Ok((
             Self {
+                id,
                 rx,
-                state: State::default(),
+                metrics,
+                observers: config.observers.clone(),
+                flagged: Vec::new(),
+                state: State {
+                    accounts: Accounts::default(),
+                    txs,
+                    fees: config.fees.clone(),
+                    dispute_window: config.dispute_window,
+                    representment_window: config.representment_window,
+                    negative_balance: config.negative_balance,
+                    redispute: config.redispute,
+                    screening: Screening::new(config.fraud.clone()),
+                    stats: Aggregates::default(),
+                    ledgers: config.check_invariants.then(HashMap::new),
+                    holds: config.check_invariants.then(HashMap::new),
+                    journal: match (config.ledger, config.check_invariants) {
+                        (true, _) => Some(Journal::default()),
+                        (false, true) => Some(Journal::balances_only()),
+                        (false, false) => None,
+                    },
+                    seq: 0,
+                    undo: None,
+                },
             },
             tx,
         ))
     }
 
+    #[tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(client = tx.client(), tx_id = tx.tx_id(), seq = self.state.seq),
+        err(level = "debug", Display)
+    )]
     fn process_tx(&mut self, tx: Transaction) -> Result<(), Error> {
+        // the strictest of the rules matched applies, flags are only reported
+        let mut blocked = None;
+        for (rule, action) in self.state.screen(&tx) {
+            match action {
+                Action::Flag => self.flag(rule),
+                _ if blocked.is_some_and(|(_, strictest)| strictest >= action) => {}
+                _ => blocked = Some((rule, action)),
+            }
+        }
+        if let Some((rule, action)) = blocked {
+            if action == Action::Freeze {
+                self.state.freeze(tx.client())?;
+            }
+            return Err(Error::Fraud(rule));
+        }
         match tx {
             Deposit {
                 client,
@@ -124,70 +696,271 @@ impl Worker {
                 tx_id,
             } => self.state.deposit(client, tx_id, value),
             // Withdrawal are not inserted in the tx store because they cannot be disputed
-            Withdrawal { client, value, .. } => self.state.withdraw(client, value),
-            Dispute { tx_id, client } => self.state.dispute(client, tx_id),
-            Chargeback { tx_id, client } => self.state.resolve(client, tx_id, |account, tx| {
-                Ok(account.chargeback(tx.value)?.freeze().into())
-            }),
-            Resolve { tx_id, client } => self.state.resolve(client, tx_id, |account, tx| {
-                Ok(account.release_funds(tx.value)?.into())
-            }),
+            Withdrawal {
+                client,
+                value,
+                tx_id,
+            } => self.state.withdraw(client, tx_id, value),
+            Dispute {
+                tx_id,
+                client,
+                value,
+            } => self.state.dispute(client, tx_id, value),
+            Chargeback {
+                tx_id,
+                client,
+                value,
+            } => {
+                let fee = self.state.fees.chargeback();
+                let closing = Closing::Chargeback;
+                self.state
+                    .resolve(client, tx_id, value, fee, closing, |account, amount| {
+                        Ok(account.chargeback(amount, fee)?)
+                    })
+            }
+            Resolve {
+                tx_id,
+                client,
+                value,
+            } => {
+                let closing = Closing::Resolve;
+                self.state.resolve(
+                    client,
+                    tx_id,
+                    value,
+                    Value::ZERO,
+                    closing,
+                    |account, amount| Ok(account.release_funds(amount)?),
+                )
+            }
+            Representment { tx_id, client } => self.state.represent(client, tx_id),
         }
     }
 
-    pub fn run(mut self) -> JoinHandle<State> {
+    // A violation means the state can no longer be trusted, so the worker stops right there
+    fn check_invariants(
+        &mut self,
+        tx: &Transaction,
+        before: Option<&Account>,
+        referenced: Option<Value>,
+    ) -> Result<(), Error> {
+        match self.state.check_invariants(tx, before, referenced) {
+            Ok(None) => {}
+            Ok(Some(violation)) => {
+                tracing::error!(%violation, "ledger invariant violated");
+                return Err(Error::InvariantViolated(violation));
+            }
+            Err(e) => tracing::warn!(error = %e, "could not check ledger invariants"),
+        }
+        Ok(())
+    }
+
+    fn flag(&mut self, rule: &'static str) {
+        tracing::warn!(rule, "transaction flagged by fraud rule");
+        self.metrics.flag(rule);
+        if !self.observers.is_empty() {
+            self.flagged.push(rule);
+        }
+    }
+
+    // Process transactions as a single batch of store writes.
+    // If the batch cannot be committed, none of the transactions is applied.
+    fn process_batch(&mut self, batch: Vec<(u64, Transaction)>) -> Result<(), Error> {
+        self.state.begin_batch()?;
+        // Only reported to observers once the batch is committed
+        let mut observed = Vec::new();
+        // Only counted once the batch is committed too, as rejected if it is not
+        let mut counted = Vec::with_capacity(batch.len());
+        for (seq, tx) in batch {
+            self.state.seq = seq;
+            let before = self.state.accounts.get(&tx.client()).copied();
+            let referenced = self.state.referenced(&tx);
+            let start = Instant::now();
+            // do not block on errors
+            // transactions that result in errors will be ignored and will not put
+            // the system in an invalid state
+            let result = self.process_tx(tx.clone());
+            counted.push((
+                tx.client(),
+                result.as_ref().err().map(|e| e.kind()),
+                start.elapsed(),
+            ));
+            if result.is_ok() {
+                self.check_invariants(&tx, before.as_ref(), referenced)?;
+            }
+            let after = self.state.accounts.get(&tx.client()).copied();
+            self.state
+                .record(&tx, before.as_ref(), after.as_ref(), result.is_ok());
+            if !self.observers.is_empty() {
+                observed.push(Outcome {
+                    flagged: std::mem::take(&mut self.flagged),
+                    tx,
+                    before,
+                    after,
+                    result,
+                });
+            }
+        }
+        let committed = self.state.commit_batch();
+        if committed.is_err() {
+            self.metrics.failed_batch();
+        }
+        for (client, rejected, elapsed) in counted {
+            match &committed {
+                Ok(()) => self.metrics.transaction(rejected, elapsed),
+                // the whole batch is discarded
+                Err(e) => {
+                    self.metrics.transaction(Some(e.kind()), elapsed);
+                    self.state.stats.entry(client).or_default().rejected += 1;
+                }
+            }
+        }
+        for Outcome {
+            tx,
+            flagged,
+            before,
+            after,
+            result,
+        } in observed
+        {
+            for rule in flagged {
+                self.observers.on_flagged(&tx, rule);
+            }
+            match (&result, &committed, after) {
+                (Ok(()), Ok(()), Some(after)) => {
+                    self.observers.on_applied(&tx, before.as_ref(), &after)
+                }
+                (_, Err(e), _) | (Err(e), _, _) => self.observers.on_rejected(&tx, e),
+                (Ok(()), Ok(()), None) => unreachable!("applied transactions leave an account"),
+            }
+            // frozen accounts may come from rejected transactions too, e.g. by fraud rules
+            let was_frozen = matches!(before, Some(Account::Frozen(_)));
+            if committed.is_ok() && !was_frozen && matches!(after, Some(Account::Frozen(_))) {
+                self.observers.on_frozen(tx.client());
+            }
+        }
+        committed
+    }
+
+    /// Process transactions until the engine hangs up.
+    ///
+    /// A batch which cannot be committed stops the worker with the error, rather than going on
+    /// without its transactions, as does a violated invariant.
+    pub fn run(mut self) -> JoinHandle<Result<State, Error>> {
         std::thread::spawn(move || {
+            let span = tracing::info_span!("worker", worker = self.id);
+            let _entered = span.enter();
             // recv() will only fail on disconnection
-            while let Ok(tx) = self.rx.recv() {
-                // do not block on errors
-                // transactions that result in errors will be ignored and will not put
-                // the system in an invalid state
-                if let Err(_e) = self.process_tx(tx) {
-                    // eprintln!("error while processing tx: {e}"); // some logging machinery would be better suited for this
+            while let Ok(msg) = self.rx.recv() {
+                // Group the transactions already queued, up to the first request which is not one
+                let mut batch = Vec::new();
+                let mut next = Some(msg);
+                while let Some(Msg::Tx(seq, tx)) = next {
+                    self.metrics.dequeued();
+                    batch.push((seq, tx));
+                    next = if batch.len() < BATCH_SIZE {
+                        self.rx.try_recv().ok()
+                    } else {
+                        None
+                    };
+                }
+                if !batch.is_empty() {
+                    if let Err(e) = self.process_batch(batch) {
+                        tracing::error!(error = %e, "could not commit a batch, stopping");
+                        return Err(e);
+                    }
+                }
+                match next {
+                    // the engine may have given up waiting, nothing to do if it's gone
+                    Some(Msg::Compact(reply)) => {
+                        let _ = reply.send(self.state.txs.compact());
+                    }
+                    Some(Msg::Stats(reply)) => {
+                        let _ = reply.send(self.state.txs.stats());
+                    }
+                    Some(Msg::Restore(accounts)) => self.state.accounts.extend(accounts),
+                    Some(Msg::Tx(..)) | None => {}
                 }
             }
-            self.state
+            Ok(self.state)
         })
     }
 }
 
 /// View on a (sub)set of accounts and their transactions
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct State {
     // if there are lots of clients this could be a tiered system
     // but it's fine as we only have u16::MAX accounts at most
     accounts: Accounts,
     // Record of transactions issued by clients in this partition
-    txs: TransactionStore,
+    txs: Box<dyn TxStore>,
+    fees: FeeSchedule,
+    dispute_window: Option<u64>,
+    representment_window: Option<u64>,
+    negative_balance: NegativeBalance,
+    redispute: Redispute,
+    // Fraud rules, along with the recent activity of clients
+    screening: Screening,
+    // Position in the input of the transaction being processed
+    seq: u64,
+    // Activity of each client, for reporting
+    stats: Aggregates,
+    // Movements of funds of each client, only kept when checking invariants
+    ledgers: Option<HashMap<Client, Ledger>>,
+    // Change in what the records of each client hold since its ledger was opened, only kept
+    // when checking invariants rather than summing them up from the store after every transaction
+    holds: Option<HashMap<Client, Value>>,
+    // Postings of applied transactions, only kept if asked for
+    journal: Option<Journal>,
+    // `None` if no batch is in progress.
+    undo: Option<Undo>,
+}
+
+// State before the current batch, for clients changed in it
+#[derive(Debug, Default)]
+struct Undo {
+    accounts: HashMap<Client, Option<Account>>,
+    stats: HashMap<Client, Option<ClientStats>>,
+    ledgers: HashMap<Client, Option<Ledger>>,
+    holds: HashMap<Client, Option<Value>>,
+    activity: HashMap<Client, Option<Activity>>,
+    // Postings in the journal before the batch
+    postings: usize,
 }
 
 pub type Accounts = HashMap<Client, Account>;
 
 impl State {
-    fn fetch_account(
-        &self,
-        client: Client,
-        create_on_miss: bool,
-    ) -> Result<AccountInner<Active>, Error> {
+    // Which operations are legal in the remaining states is up to `Account`
+    fn fetch_account(&self, client: Client, create_on_miss: bool) -> Result<Account, Error> {
         if let Some(account) = self.accounts.get(&client) {
             match account {
-                Account::Active(inner) => Ok(*inner),
                 Account::Frozen(_) => Err(Error::AccountFrozen),
+                Account::Closed(_) => Err(Error::AccountClosed),
+                other => Ok(*other),
             }
         } else if create_on_miss {
-            Ok(AccountInner::default())
+            Ok(Account::default())
         } else {
             Err(Error::AccountNotFound)
         }
     }
 
-    // Fetch account state and specific transaction
-    fn fetch_all(
-        &self,
-        client: Client,
-        tx_id: TxId,
-    ) -> Result<(AccountInner<Active>, TxRecord), Error> {
-        let account = self.fetch_account(client, false)?;
+    // Fetch account state and specific transaction.
+    // Transactions which froze the account by being charged back are reported as such.
+    fn fetch_all(&self, client: Client, tx_id: TxId) -> Result<(Account, TxRecord), Error> {
+        let account = self.fetch_account(client, false);
+        if let Err(Error::AccountFrozen) = account {
+            if let Ok(TxRecord {
+                status: TxStatus::ChargedBack | TxStatus::Represented,
+                ..
+            }) = self.txs.get(client, tx_id)
+            {
+                return Err(Error::AlreadyChargedBack);
+            }
+        }
+        let account = account?;
         let tx = self.txs.get(client, tx_id)?;
         Ok((account, tx))
     }
@@ -200,65 +973,398 @@ impl State {
         client: Client,
         tx_id: TxId,
         account: Account,
-        record: Option<TxRecord>,
+        record: TxRecord,
     ) -> Result<(), Error> {
-        if let Some(record) = record {
-            self.txs.insert(client, tx_id, record)?;
-        } else {
-            self.txs.remove(client, tx_id)?;
+        self.txs.insert(client, tx_id, record)?;
+        self.set_account(client, account);
+        Ok(())
+    }
+
+    // Account for `change` in what the records of `client` hold
+    fn hold(&mut self, client: Client, change: Value) {
+        let Some(holds) = &mut self.holds else {
+            return;
+        };
+        if let Some(undo) = &mut self.undo {
+            let previous = holds.get(&client).copied();
+            undo.holds.entry(client).or_insert(previous);
+        }
+        *holds.entry(client).or_default() += change;
+    }
+
+    fn set_account(&mut self, client: Client, account: Account) {
+        let previous = self.accounts.insert(client, account);
+        if let Some(undo) = &mut self.undo {
+            undo.accounts.entry(client).or_insert(previous);
+        }
+    }
+
+    // Account for `tx` in the activity of its client
+    fn record(
+        &mut self,
+        tx: &Transaction,
+        before: Option<&Account>,
+        after: Option<&Account>,
+        applied: bool,
+    ) {
+        let client = tx.client();
+        if let Some(undo) = &mut self.undo {
+            let previous = self.stats.get(&client).copied();
+            undo.stats.entry(client).or_insert(previous);
+        }
+        let stats = self.stats.entry(client).or_default();
+        stats.record(tx, before, after, applied);
+    }
+
+    // Amount charged back or re-presented by `tx`, only looked up when checking invariants
+    fn referenced(&self, tx: &Transaction) -> Option<Value> {
+        self.ledgers.as_ref()?;
+        match tx {
+            Chargeback {
+                client,
+                tx_id,
+                value,
+            } => {
+                let record = self.txs.get(*client, *tx_id).ok()?;
+                Some(value.unwrap_or(record.held))
+            }
+            Representment { client, tx_id } => self
+                .txs
+                .get(*client, *tx_id)
+                .ok()
+                .map(|record| record.charged_back),
+            _ => None,
         }
-        self.accounts.insert(client, account);
+    }
+
+    // Account for the applied `tx` in the ledger of its client and check the invariants of its
+    // account, if enabled. `before` is the account before `tx`, `referenced` as above.
+    fn check_invariants(
+        &mut self,
+        tx: &Transaction,
+        before: Option<&Account>,
+        referenced: Option<Value>,
+    ) -> Result<Option<Violation>, Error> {
+        let Some(ledgers) = &mut self.ledgers else {
+            return Ok(None);
+        };
+        let client = tx.client();
+        let fee = match tx {
+            // applied withdrawals had their fee computed already
+            Withdrawal { value, .. } => self.fees.withdrawal(*value).unwrap_or_default(),
+            Chargeback { .. } => self.fees.chargeback(),
+            _ => Value::ZERO,
+        };
+        if let Some(undo) = &mut self.undo {
+            let previous = ledgers.get(&client).copied();
+            undo.ledgers.entry(client).or_insert(previous);
+        }
+        let ledger = ledgers.entry(client).or_insert_with(|| Ledger::new(before));
+        ledger.record(tx, referenced, fee);
+
+        let disputed = ledger.opening_held
+            + self
+                .holds
+                .as_ref()
+                .and_then(|holds| holds.get(&client))
+                .copied()
+                .unwrap_or_default();
+        let account = self.accounts.get(&client).ok_or(Error::AccountNotFound)?;
+        // kept whenever invariants are checked
+        let booked = self
+            .journal
+            .as_ref()
+            .map_or_else(Default::default, |journal| journal.client(client));
+        Ok(invariant::check(tx, account, ledger, disputed, booked).err())
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        self.txs.begin_batch()?;
+        self.undo = Some(Undo {
+            postings: self.journal.as_ref().map_or(0, Journal::len),
+            ..Undo::default()
+        });
         Ok(())
     }
 
+    // On failure, restore accounts as they were before the batch to stay consistent
+    // with the store, which discarded all the batch writes.
+    fn commit_batch(&mut self) -> Result<(), Error> {
+        let undo = self.undo.take().unwrap_or_default();
+        if let Err(e) = self.txs.commit_batch() {
+            for (client, account) in undo.accounts {
+                match account {
+                    Some(account) => self.accounts.insert(client, account),
+                    None => self.accounts.remove(&client),
+                };
+            }
+            for (client, stats) in undo.stats {
+                match stats {
+                    Some(stats) => self.stats.insert(client, stats),
+                    None => self.stats.remove(&client),
+                };
+            }
+            if let Some(journal) = &mut self.journal {
+                journal.truncate(undo.postings);
+            }
+            for (client, activity) in undo.activity {
+                self.screening.restore(client, activity);
+            }
+            if let Some(ledgers) = &mut self.ledgers {
+                for (client, ledger) in undo.ledgers {
+                    match ledger {
+                        Some(ledger) => ledgers.insert(client, ledger),
+                        None => ledgers.remove(&client),
+                    };
+                }
+            }
+            if let Some(holds) = &mut self.holds {
+                for (client, held) in undo.holds {
+                    match held {
+                        Some(held) => holds.insert(client, held),
+                        None => holds.remove(&client),
+                    };
+                }
+            }
+            return Err(e.into());
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.settle();
+        }
+        Ok(())
+    }
+
+    // Fraud rules matched by `tx`, with their action
+    fn screen(&mut self, tx: &Transaction) -> Vec<(&'static str, Action)> {
+        if let Some(undo) = &mut self.undo {
+            let client = tx.client();
+            undo.activity
+                .entry(client)
+                .or_insert_with(|| self.screening.activity(client));
+        }
+        let account = self.accounts.get(&tx.client());
+        self.screening
+            .screen(tx, account)
+            .into_iter()
+            .map(|rule| (rule.name(), rule.action()))
+            .collect()
+    }
+
+    // Book `amount` moved by the transaction being processed, if the journal is kept
+    fn post(&mut self, tx: TxId, debit: LedgerAccount, credit: LedgerAccount, amount: Value) {
+        if let Some(journal) = &mut self.journal {
+            journal.post(Posting {
+                seq: self.seq,
+                tx,
+                debit,
+                credit,
+                amount,
+            });
+        }
+    }
+
+    // Only existing accounts are frozen, those which are locked already are left as they are
+    fn freeze(&mut self, client: Client) -> Result<(), Error> {
+        match self.fetch_account(client, false) {
+            Ok(account) => {
+                let account = account.freeze()?;
+                self.set_account(client, account);
+                Ok(())
+            }
+            Err(Error::AccountNotFound | Error::AccountFrozen | Error::AccountClosed) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Fees are charged together with the operation they're due for, record them before touching
+    // the account for the same reason as above.
+    fn record_fee(&mut self, client: Client, tx_id: TxId, fee: Value) -> Result<(), Error> {
+        if !fee.is_zero() {
+            self.txs.insert_fee(client, tx_id, fee)?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
     fn deposit(&mut self, client: Client, tx_id: TxId, value: Value) -> Result<(), Error> {
         let new_account = self.fetch_account(client, true)?.deposit(value)?;
         self.write_back(
             client,
             tx_id,
-            new_account.into(),
-            Some(TxRecord {
-                value,
-                status: TxStatus::Undisputed,
-            }),
-        )
+            new_account,
+            TxRecord::new(TxKind::Deposit, value, self.seq),
+        )?;
+        let available = LedgerAccount::Available(client);
+        self.post(tx_id, LedgerAccount::Settlement, available, value);
+        Ok(())
     }
 
-    fn withdraw(&mut self, client: Client, value: Value) -> Result<(), Error> {
-        let acc = self.fetch_account(client, false)?.withdraw(value)?;
-        self.accounts.insert(client, acc.into());
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn withdraw(&mut self, client: Client, tx_id: TxId, value: Value) -> Result<(), Error> {
+        let fee = self.fees.withdrawal(value).ok_or(Error::FeeOverflow)?;
+        let acc = self
+            .fetch_account(client, false)?
+            .withdraw_with_fee(value, fee)?;
+        self.record_fee(client, tx_id, fee)?;
+        self.set_account(client, acc);
+        let available = LedgerAccount::Available(client);
+        self.post(tx_id, available, LedgerAccount::Settlement, value);
+        self.post(tx_id, available, LedgerAccount::Fees, fee);
         // withdraws are not stored since they cannot be disputed, see assumptions in README
         Ok(())
     }
 
-    fn dispute(&mut self, client: Client, tx_id: TxId) -> Result<(), Error> {
+    #[tracing::instrument(level = "trace", skip(self))]
+    // `value` is the part disputed, the rest of the transaction if `None`
+    fn dispute(&mut self, client: Client, tx_id: TxId, value: Option<Value>) -> Result<(), Error> {
         let (account, tx) = self.fetch_all(client, tx_id)?;
-        if let TxStatus::Undisputed = tx.status {
-            let account = account.freeze_funds(tx.value)?;
-            self.write_back(
-                client,
-                tx_id,
-                account.into(),
-                Some(TxRecord {
-                    value: tx.value,
-                    status: TxStatus::Disputed,
-                }),
-            )
-        } else {
-            Err(Error::NotAvailableForDispute)
+        // what was not disputed yet can be, even if other parts of the transaction were,
+        // along with what was resolved if allowed
+        let resolved = tx.value - tx.disputable - tx.held - tx.charged_back;
+        let open = match self.redispute {
+            Redispute::Reject => tx.disputable,
+            Redispute::Allow => tx.disputable + resolved,
+        };
+        match tx.status {
+            TxStatus::ChargedBack | TxStatus::Represented => return Err(Error::AlreadyChargedBack),
+            TxStatus::Resolved if open.is_zero() => return Err(Error::AlreadyResolved),
+            _ if open.is_zero() => return Err(Error::NotAvailableForDispute),
+            _ => {}
         }
+        if self
+            .dispute_window
+            .is_some_and(|window| self.seq.saturating_sub(tx.seq) > window)
+        {
+            return Err(Error::DisputeWindowExpired);
+        }
+        let amount = value.unwrap_or(open);
+        if amount <= Value::ZERO || amount > open {
+            return Err(Error::InvalidAmount);
+        }
+        if self.negative_balance == NegativeBalance::Reject && account.available() < amount {
+            return Err(account::AccountError::NotEnoughFunds.into());
+        }
+        let account = account.freeze_funds(amount)?;
+        // funds never disputed go first, then resolved ones
+        let record = TxRecord {
+            disputable: tx.disputable - amount.min(tx.disputable),
+            held: tx.held + amount,
+            ..tx.with_status(TxStatus::Disputed, self.seq)
+        };
+        self.write_back(client, tx_id, account, record)?;
+        self.hold(client, amount);
+        let (available, held) = (
+            LedgerAccount::Available(client),
+            LedgerAccount::Held(client),
+        );
+        self.post(tx_id, available, held, amount);
+        Ok(())
     }
 
-    fn resolve<F>(&mut self, client: Client, tx_id: TxId, f: F) -> Result<(), Error>
+    // Close `value` of the funds held by a dispute, all of them if `None`.
+    // `f` takes them out of the account and charges `fee`, it's only passed here to be recorded.
+    #[tracing::instrument(level = "trace", skip(self, f))]
+    fn resolve<F>(
+        &mut self,
+        client: Client,
+        tx_id: TxId,
+        value: Option<Value>,
+        fee: Value,
+        closing: Closing,
+        f: F,
+    ) -> Result<(), Error>
     where
-        F: FnOnce(&AccountInner<Active>, &TxRecord) -> Result<Account, Error>,
+        F: FnOnce(&Account, Value) -> Result<Account, Error>,
     {
         let (account, tx) = self.fetch_all(client, tx_id)?;
-        if let TxStatus::Disputed = tx.status {
-            let account = f(&account, &tx)?;
-            self.write_back(client, tx_id, account, None)
+        match tx.status {
+            TxStatus::Disputed => {}
+            TxStatus::Undisputed => return Err(Error::NoDisputeActive),
+            TxStatus::Resolved => return Err(Error::AlreadyResolved),
+            TxStatus::ChargedBack | TxStatus::Represented => return Err(Error::AlreadyChargedBack),
+        }
+        let amount = value.unwrap_or(tx.held);
+        if amount <= Value::ZERO || amount > tx.held {
+            return Err(Error::InvalidAmount);
+        }
+        let held = tx.held - amount;
+        // chargebacks freeze the account, what they leave held would be stuck there for good
+        let released = match closing {
+            Closing::Chargeback => held,
+            Closing::Resolve => Value::ZERO,
+        };
+        let account = match released.is_zero() {
+            true => account,
+            false => account.release_funds(released)?,
+        };
+        let account = f(&account, amount)?;
+        let (record, to) = match closing {
+            // charged back transactions may be re-presented
+            Closing::Chargeback => (
+                TxRecord {
+                    held: Value::ZERO,
+                    charged_back: tx.charged_back + amount,
+                    ..tx.with_status(TxStatus::ChargedBack, self.seq)
+                },
+                LedgerAccount::ChargebackLoss,
+            ),
+            // still in dispute as long as some of it is held
+            Closing::Resolve if !held.is_zero() => {
+                (TxRecord { held, ..tx }, LedgerAccount::Available(client))
+            }
+            Closing::Resolve => (
+                TxRecord {
+                    held,
+                    ..tx.with_status(TxStatus::Resolved, self.seq)
+                },
+                LedgerAccount::Available(client),
+            ),
+        };
+        let change = record.held - tx.held;
+        // the fee goes last, so that no fee is left behind for a chargeback which failed
+        self.txs.insert(client, tx_id, record)?;
+        self.record_fee(client, tx_id, fee)?;
+        self.set_account(client, account);
+        self.hold(client, change);
+        let (held, available) = (
+            LedgerAccount::Held(client),
+            LedgerAccount::Available(client),
+        );
+        self.post(tx_id, held, available, released);
+        self.post(tx_id, held, to, amount);
+        self.post(tx_id, available, LedgerAccount::Fees, fee);
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn represent(&mut self, client: Client, tx_id: TxId) -> Result<(), Error> {
+        // accounts are frozen by chargebacks, `fetch_account` would turn them down
+        let account = *self.accounts.get(&client).ok_or(Error::AccountNotFound)?;
+        let tx = self.txs.get(client, tx_id)?;
+        if let TxStatus::ChargedBack = tx.status {
+            if self
+                .representment_window
+                .is_some_and(|window| self.seq.saturating_sub(tx.updated_seq) > window)
+            {
+                return Err(Error::RepresentmentWindowExpired);
+            }
+            let account = account.represent(tx.charged_back)?;
+            self.write_back(
+                client,
+                tx_id,
+                account,
+                tx.with_status(TxStatus::Represented, self.seq),
+            )?;
+            let available = LedgerAccount::Available(client);
+            self.post(
+                tx_id,
+                LedgerAccount::ChargebackLoss,
+                available,
+                tx.charged_back,
+            );
+            Ok(())
         } else {
-            Err(Error::NoDisputeActive)
+            Err(Error::NotChargedBack)
         }
     }
 }
@@ -266,7 +1372,8 @@ impl State {
 #[cfg(test)]
 mod test {
     use super::*;
-    use quickcheck::TestResult;
+    use crate::store::tests::{Fault, FaultyStore};
+    use quickcheck::{QuickCheck, TestResult};
     use quickcheck_macros::*;
     const CLIENT: u16 = 0;
 
@@ -287,15 +1394,54 @@ mod test {
     }
 
     fn dispute(client: Client, tx_id: TxId) -> Transaction {
-        Transaction::Dispute { client, tx_id }
+        Transaction::Dispute {
+            client,
+            tx_id,
+            value: None,
+        }
     }
 
     fn resolve(client: Client, tx_id: TxId) -> Transaction {
-        Transaction::Resolve { client, tx_id }
+        Transaction::Resolve {
+            client,
+            tx_id,
+            value: None,
+        }
     }
 
     fn chargeback(client: Client, tx_id: TxId) -> Transaction {
-        Transaction::Chargeback { client, tx_id }
+        Transaction::Chargeback {
+            client,
+            tx_id,
+            value: None,
+        }
+    }
+
+    fn representment(client: Client, tx_id: TxId) -> Transaction {
+        Transaction::Representment { client, tx_id }
+    }
+
+    // `tx` applied to `amount` only
+    fn partial(tx: Transaction, amount: i64) -> Transaction {
+        let part = Some(Value::new(amount, 0));
+        match tx {
+            Transaction::Dispute { client, tx_id, .. } => Transaction::Dispute {
+                client,
+                tx_id,
+                value: part,
+            },
+            Transaction::Resolve { client, tx_id, .. } => Transaction::Resolve {
+                client,
+                tx_id,
+                value: part,
+            },
+            Transaction::Chargeback { client, tx_id, .. } => Transaction::Chargeback {
+                client,
+                tx_id,
+                value: part,
+            },
+            other => other,
+        }
     }
 
     #[quickcheck]
@@ -320,12 +1466,11 @@ mod test {
     fn test_withdraw(tx: Transaction) -> TestResult {
         if let Transaction::Withdrawal { client, .. } = tx {
             TestResult::from_bool(
-                Engine::new(1)
+                !Engine::new(1)
                     .unwrap()
                     .run([tx].into_iter())
                     .unwrap()
-                    .get(&client)
-                    .is_none(),
+                    .contains_key(&client),
             )
         } else {
             TestResult::discard()
@@ -334,7 +1479,9 @@ mod test {
 
     #[test]
     fn test_deposit_withdraw() {
-        let mut eng = Worker::new().unwrap().0;
+        let mut eng = Worker::new(0, &Config::default(), DEFAULT_CHANNEL_CAPACITY)
+            .unwrap()
+            .0;
         eng.process_tx(deposit(CLIENT, 0, Value::TEN)).unwrap();
         assert_eq!(eng.state.accounts.get(&CLIENT).unwrap().available(), Value::TEN);
         eng.process_tx(withdraw(CLIENT, 1, Value::ONE)).unwrap();
@@ -346,7 +1493,9 @@ mod test {
 
     #[test]
     fn test_freeze_release() {
-        let mut eng = Worker::new().unwrap().0;
+        let mut eng = Worker::new(0, &Config::default(), DEFAULT_CHANNEL_CAPACITY)
+            .unwrap()
+            .0;
         eng.process_tx(deposit(CLIENT, 0, Value::TEN)).unwrap();
         eng.process_tx(deposit(CLIENT, 1, Value::ONE)).unwrap();
         eng.process_tx(dispute(CLIENT, 1)).unwrap();
@@ -362,7 +1511,9 @@ mod test {
 
     #[test]
     fn test_freeze_chargeback() {
-        let mut eng = Worker::new().unwrap().0;
+        let mut eng = Worker::new(0, &Config::default(), DEFAULT_CHANNEL_CAPACITY)
+            .unwrap()
+            .0;
         eng.process_tx(deposit(CLIENT, 0, Value::TEN)).unwrap();
         eng.process_tx(deposit(CLIENT, 1, Value::ONE)).unwrap();
         eng.process_tx(dispute(CLIENT, 1)).unwrap();
@@ -380,14 +1531,741 @@ mod test {
         assert!(matches!(eng.state.accounts.get(&CLIENT).unwrap(), Account::Frozen(_), ));
     }
 
-    #[quickcheck]
-    fn test_parallelism_is_correct(batch: Vec<Transaction>) {
+    fn fees() -> FeeSchedule {
+        "[withdrawal]\nflat = \"0.5\"\npercentage = \"10\"\n[chargeback]\nflat = \"2\""
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_withdrawal_fee() {
+        let mut eng = Worker::new(
+            0,
+            &Config {
+                fees: fees(),
+                ..Config::default()
+            },
+            DEFAULT_CHANNEL_CAPACITY,
+        )
+        .unwrap()
+        .0;
+        eng.process_tx(deposit(CLIENT, 0, Value::TEN)).unwrap();
+        eng.process_tx(withdraw(CLIENT, 1, Value::new(5, 0))).unwrap();
+        let account = eng.state.accounts.get(&CLIENT).unwrap();
+        assert_eq!(account.available(), Value::new(4, 0));
+        assert_eq!(account.fees(), Value::ONE);
+        assert_eq!(eng.state.txs.get_fee(CLIENT, 1).unwrap(), Value::ONE);
+        // not enough funds to cover the fee
+        assert!(eng.process_tx(withdraw(CLIENT, 2, Value::new(4, 0))).is_err());
+        assert_eq!(eng.state.accounts.get(&CLIENT).unwrap().available(), Value::new(4, 0));
+        assert!(eng.state.txs.get_fee(CLIENT, 2).is_err());
+        assert!(matches!(
+            eng.process_tx(withdraw(CLIENT, 3, Value::MAX)),
+            Err(Error::FeeOverflow)
+        ));
+    }
+
+    #[test]
+    fn test_chargeback_fee() {
+        let mut eng = Worker::new(
+            0,
+            &Config {
+                fees: fees(),
+                ..Config::default()
+            },
+            DEFAULT_CHANNEL_CAPACITY,
+        )
+        .unwrap()
+        .0;
+        eng.process_tx(deposit(CLIENT, 0, Value::ONE)).unwrap();
+        eng.process_tx(deposit(CLIENT, 1, Value::TEN)).unwrap();
+        eng.process_tx(dispute(CLIENT, 1)).unwrap();
+        eng.process_tx(chargeback(CLIENT, 1)).unwrap();
+        let account = eng.state.accounts.get(&CLIENT).unwrap();
+        // the fee can take the balance negative
+        assert_eq!(account.available(), -Value::ONE);
+        assert_eq!(account.held(), Value::ZERO);
+        assert_eq!(account.fees(), Value::TWO);
+        assert_eq!(eng.state.txs.get_fee(CLIENT, 1).unwrap(), Value::TWO);
+    }
+
+    #[test]
+    fn test_compact_between_batches() {
+        let mut engine = Engine::new(2).unwrap();
+        for tx_id in 0..10 {
+            engine
+                .feed(deposit(tx_id as Client, tx_id, Value::ONE))
+                .unwrap();
+        }
+        engine.feed(dispute(1, 1)).unwrap();
+        engine.compact().unwrap();
+        let stats = engine.stats().unwrap();
+        assert_eq!(stats.undisputed, 9);
+        assert_eq!(stats.disputed, 1);
+        assert_eq!(stats.clients.len(), 10);
+        engine.feed(resolve(1, 1)).unwrap();
+        assert_eq!(engine.stats().unwrap().disputed, 0);
+        assert_eq!(engine.finish().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn test_failed_commit_is_consistent() {
+        let config = Config {
+            store: Backend::Custom(std::sync::Arc::new(|_| Ok(Box::<FaultyStore>::default()))),
+            ledger: true,
+            fraud: "[[rule]]\nrule = \"disputes\"\nmax = 1\naction = \"freeze\""
+                .parse()
+                .unwrap(),
+            ..Config::default()
+        };
+        let mut eng = Worker::new(0, &config, DEFAULT_CHANNEL_CAPACITY).unwrap().0;
+        // outside of a batch writes are applied right away
+        eng.process_tx(deposit(CLIENT, 0, Value::TEN)).unwrap();
+        let activity = eng.state.screening.activity(CLIENT);
+        assert!(eng
+            .process_batch(vec![
+                (1, deposit(CLIENT, 1, Value::ONE)),
+                (2, dispute(CLIENT, 0)),
+                (3, deposit(CLIENT + 1, 2, Value::ONE)),
+            ])
+            .is_err());
+        assert_eq!(eng.state.accounts.len(), 1);
+        let account = eng.state.accounts.get(&CLIENT).unwrap();
+        assert_eq!(account.available(), Value::TEN);
+        assert_eq!(account.held(), Value::ZERO);
+        assert!(matches!(
+            eng.state.txs.get(CLIENT, 0).unwrap().status,
+            TxStatus::Undisputed
+        ));
+        assert!(eng.state.txs.get(CLIENT, 1).is_err());
+        let journal = eng.state.journal.as_ref().unwrap();
+        assert_eq!(journal.len(), 1);
+        assert_eq!(journal.client(CLIENT), (Value::TEN, Value::ZERO));
+        // the discarded dispute does not count towards fraud rules
+        assert_eq!(eng.state.screening.activity(CLIENT), activity);
+        assert_eq!(eng.state.screening.activity(CLIENT + 1), None);
+    }
+
+    #[test]
+    fn test_worker_failure() {
+        let config = Config {
+            store: Backend::Custom(std::sync::Arc::new(|worker| match worker {
+                0 => Ok(Box::<store::MemoryStore>::default()),
+                _ => Ok(Box::new(FaultyStore::new(Fault::Panic))),
+            })),
+            ..Config::default()
+        };
+        let mut engine = Engine::with_config(2, config).unwrap();
+        engine.feed(deposit(2, 0, Value::TEN)).unwrap();
+        // the failure surfaces once the worker stopped receiving
+        let failure = (1..)
+            .find_map(|tx_id| engine.feed(deposit(1, tx_id, Value::ONE)).err())
+            .unwrap();
+        assert!(matches!(
+            failure,
+            Error::WorkerFailed { worker: 1, ref cause } if cause == "disk on fire"
+        ));
+        engine.feed(deposit(2, 1, Value::ONE)).unwrap();
+
+        let finished = engine.finish_partial();
+        assert_eq!(finished.accounts.len(), 1);
+        assert_eq!(finished.accounts[&2].available(), Value::new(11, 0));
+        assert!(matches!(
+            finished.failures[..],
+            [Error::WorkerFailed { worker: 1, .. }]
+        ));
+    }
+
+    #[test]
+    fn test_failed_commit_stops_worker() {
+        let config = Config {
+            store: Backend::Custom(std::sync::Arc::new(|_| Ok(Box::<FaultyStore>::default()))),
+            ..Config::default()
+        };
+        let mut engine = Engine::with_config(1, config).unwrap();
+        engine.feed(deposit(CLIENT, 0, Value::TEN)).unwrap();
+        // rather than leaving the deposit out of the accounts
+        assert!(matches!(
+            engine.finish(),
+            Err(Error::WorkerFailed { worker: 0, .. })
+        ));
+    }
+
+    fn checked_worker() -> Worker {
+        let config = Config {
+            fees: "[withdrawal]\nflat = \"0.5\"\n[chargeback]\nflat = \"1\""
+                .parse()
+                .unwrap(),
+            check_invariants: true,
+            ..Config::default()
+        };
+        Worker::new(0, &config, DEFAULT_CHANNEL_CAPACITY).unwrap().0
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut eng = checked_worker();
+        eng.process_batch(vec![
+            (0, deposit(CLIENT, 0, Value::TEN)),
+            (1, deposit(CLIENT, 1, Value::TEN)),
+            (2, withdraw(CLIENT, 2, Value::TWO)),
+            (3, dispute(CLIENT, 0)),
+            (4, dispute(CLIENT, 1)),
+            (5, resolve(CLIENT, 1)),
+            (6, chargeback(CLIENT, 0)),
+        ])
+        .unwrap();
+        assert!(matches!(eng.state.accounts[&CLIENT], Account::Frozen(_)));
+    }
+
+    #[test]
+    fn test_invariant_violation() {
+        let mut eng = checked_worker();
+        eng.process_batch(vec![(0, deposit(CLIENT, 0, Value::TEN))])
+            .unwrap();
+        // funds out of thin air
+        let account = Account::default().deposit(Value::new(11, 0)).unwrap();
+        eng.state.accounts.insert(CLIENT, account);
+        let result = eng.process_batch(vec![(1, deposit(CLIENT, 1, Value::ONE))]);
+        assert!(matches!(
+            result,
+            Err(Error::InvariantViolated(Violation {
+                invariant: invariant::Invariant::TotalBalanced,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn test_ledger() {
+        let mut engine = EngineBuilder::new()
+            .workers(2)
+            .store(Backend::Memory)
+            .fees(
+                "[withdrawal]\nflat = \"0.5\"\n[chargeback]\nflat = \"1\""
+                    .parse()
+                    .unwrap(),
+            )
+            .ledger(true)
+            .build()
+            .unwrap();
+        for tx in [
+            deposit(1, 0, Value::TEN),
+            deposit(2, 1, Value::TEN),
+            withdraw(1, 2, Value::TWO),
+            dispute(1, 0),
+            dispute(2, 1),
+            resolve(2, 1),
+            chargeback(1, 0),
+            // rejected, not booked
+            withdraw(2, 3, Value::ONE_HUNDRED),
+        ] {
+            engine.feed(tx).unwrap();
+        }
+        let finished = engine.finish_partial();
+        let journal = finished.journal;
+        assert_eq!(journal.len(), 9);
+        assert!(journal.postings().is_sorted_by_key(|posting| posting.seq));
+        for (client, account) in &finished.accounts {
+            assert_eq!(
+                journal.client(*client),
+                (account.available(), account.held())
+            );
+        }
+        assert_eq!(journal.balance(LedgerAccount::ChargebackLoss), Value::TEN);
+        assert_eq!(journal.balance(LedgerAccount::Fees), Value::new(15, 1));
+        assert_eq!(
+            journal.balance(LedgerAccount::Settlement),
+            -Value::new(18, 0)
+        );
+        assert_eq!(journal.sum(), Value::ZERO);
+    }
+
+    #[test]
+    fn test_representment() {
+        let config = Config {
+            representment_window: Some(2),
+            check_invariants: true,
+            ledger: true,
+            ..Config::default()
+        };
+        let mut eng = Worker::new(0, &config, DEFAULT_CHANNEL_CAPACITY).unwrap().0;
+        let late = CLIENT + 1;
+        eng.process_batch(vec![
+            (0, deposit(CLIENT, 0, Value::TEN)),
+            (1, deposit(late, 1, Value::ONE)),
+            (2, dispute(CLIENT, 0)),
+            (3, dispute(late, 1)),
+            (4, representment(CLIENT, 0)),
+            (5, chargeback(CLIENT, 0)),
+            (6, chargeback(late, 1)),
+            (7, representment(CLIENT, 0)),
+            (8, representment(CLIENT, 0)),
+            (9, representment(late, 1)),
+        ])
+        .unwrap();
+        let account = eng.state.accounts[&CLIENT];
+        assert!(matches!(account, Account::Frozen(_)));
+        assert_eq!(
+            (account.available(), account.held()),
+            (Value::TEN, Value::ZERO)
+        );
+        assert_eq!(eng.state.accounts[&late].available(), Value::ZERO);
+        assert!(matches!(
+            eng.state.txs.get(CLIENT, 0).unwrap().status,
+            TxStatus::Represented
+        ));
+        assert!(matches!(
+            eng.state.txs.get(late, 1).unwrap().status,
+            TxStatus::ChargedBack
+        ));
+        assert_eq!(
+            eng.metrics.rejected().into_iter().collect::<Vec<_>>(),
+            [("not_charged_back", 2), ("representment_window_expired", 1)]
+        );
+        let journal = eng.state.journal.as_ref().unwrap();
+        assert_eq!(journal.balance(LedgerAccount::ChargebackLoss), Value::ONE);
+    }
+
+    #[test]
+    fn test_partial_disputes() {
+        let mut eng = checked_worker();
+        eng.process_batch(vec![
+            (0, deposit(CLIENT, 0, Value::TEN)),
+            (1, partial(dispute(CLIENT, 0), 3)),
+            (2, partial(dispute(CLIENT, 0), 4)),
+            (3, partial(dispute(CLIENT, 0), 5)),
+            (4, partial(resolve(CLIENT, 0), 2)),
+        ])
+        .unwrap();
+        let record = eng.state.txs.get(CLIENT, 0).unwrap();
+        assert_eq!(record.status, TxStatus::Disputed);
+        assert_eq!(
+            (record.disputable, record.held),
+            (Value::new(3, 0), Value::new(5, 0))
+        );
+
+        // fully resolved, what is left can still be disputed
+        eng.process_batch(vec![
+            (5, resolve(CLIENT, 0)),
+            (6, dispute(CLIENT, 0)),
+            (7, partial(resolve(CLIENT, 0), 0)),
+            (8, partial(chargeback(CLIENT, 0), 1)),
+            (9, representment(CLIENT, 0)),
+        ])
+        .unwrap();
+        // what the chargeback left held is released, less the chargeback fee
+        let account = eng.state.accounts[&CLIENT];
+        assert_eq!(
+            (account.available(), account.held()),
+            (Value::new(9, 0), Value::ZERO)
+        );
+        let record = eng.state.txs.get(CLIENT, 0).unwrap();
+        assert_eq!(record.status, TxStatus::Represented);
+        assert_eq!(
+            (record.disputable, record.held, record.charged_back),
+            (Value::ZERO, Value::ZERO, Value::ONE)
+        );
         assert_eq!(
-            Engine::new(1)
+            eng.metrics.rejected().into_iter().collect::<Vec<_>>(),
+            [("invalid_amount", 2)]
+        );
+    }
+
+    #[test]
+    fn test_closed_disputes() {
+        let mut eng = checked_worker();
+        let other = CLIENT + 1;
+        eng.process_batch(vec![
+            (0, deposit(CLIENT, 0, Value::TEN)),
+            (1, dispute(CLIENT, 0)),
+            (2, resolve(CLIENT, 0)),
+            (3, dispute(CLIENT, 0)),
+            (4, resolve(CLIENT, 0)),
+            (5, dispute(CLIENT, 1)),
+            (6, deposit(other, 2, Value::TEN)),
+            (7, dispute(other, 2)),
+            (8, chargeback(other, 2)),
+            (9, dispute(other, 2)),
+            (10, chargeback(other, 2)),
+        ])
+        .unwrap();
+        assert_eq!(
+            eng.state.txs.get(CLIENT, 0).unwrap().status,
+            TxStatus::Resolved
+        );
+        assert_eq!(
+            eng.metrics.rejected().into_iter().collect::<Vec<_>>(),
+            [
+                ("already_charged_back", 2),
+                ("already_resolved", 2),
+                ("transaction_not_found", 1)
+            ]
+        );
+
+        let config = Config {
+            redispute: Redispute::Allow,
+            check_invariants: true,
+            ..Config::default()
+        };
+        let mut eng = Worker::new(0, &config, DEFAULT_CHANNEL_CAPACITY).unwrap().0;
+        eng.process_batch(vec![
+            (0, deposit(CLIENT, 0, Value::TEN)),
+            (1, partial(dispute(CLIENT, 0), 4)),
+            (2, resolve(CLIENT, 0)),
+            (3, dispute(CLIENT, 0)),
+        ])
+        .unwrap();
+        assert_eq!(eng.state.accounts[&CLIENT].held(), Value::TEN);
+        let record = eng.state.txs.get(CLIENT, 0).unwrap();
+        assert_eq!((record.disputable, record.held), (Value::ZERO, Value::TEN));
+        assert!(eng.metrics.rejected().is_empty());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut eng = Worker::new(0, &Config::default(), DEFAULT_CHANNEL_CAPACITY)
+            .unwrap()
+            .0;
+        eng.process_batch(vec![
+            (0, deposit(CLIENT, 0, Value::TEN)),
+            (1, withdraw(CLIENT, 1, Value::TWO)),
+            (2, dispute(CLIENT, 0)),
+            (3, withdraw(CLIENT, 2, Value::TEN)),
+        ])
+        .unwrap();
+        let stats = eng.state.stats[&CLIENT];
+        assert_eq!(
+            (stats.deposited, stats.withdrawn, stats.disputed),
+            (Value::TEN, Value::TWO, Value::TEN)
+        );
+        assert_eq!(
+            (stats.deposits, stats.withdrawals, stats.rejected),
+            (1, 1, 1)
+        );
+
+        // discarded batches only count as rejected
+        let config = Config {
+            store: Backend::Custom(Arc::new(|_| Ok(Box::<FaultyStore>::default()))),
+            ..Config::default()
+        };
+        let mut eng = Worker::new(0, &config, DEFAULT_CHANNEL_CAPACITY).unwrap().0;
+        assert!(eng
+            .process_batch(vec![
+                (0, deposit(CLIENT, 0, Value::TEN)),
+                (1, deposit(CLIENT + 1, 1, Value::TEN)),
+            ])
+            .is_err());
+        assert_eq!(eng.state.stats[&CLIENT].rejected, 1);
+        assert_eq!(eng.state.stats[&CLIENT].deposited, Value::ZERO);
+        assert_eq!(eng.state.stats[&(CLIENT + 1)].rejected, 1);
+        assert_eq!(eng.metrics.processed(), 0);
+        assert_eq!(eng.metrics.rejected()["store"], 2);
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut eng = Worker::new(0, &Config::default(), DEFAULT_CHANNEL_CAPACITY)
+            .unwrap()
+            .0;
+        eng.process_batch(vec![
+            (0, deposit(CLIENT, 0, Value::TEN)),
+            (1, deposit(CLIENT, 1, Value::ONE)),
+            (2, dispute(CLIENT, 1)),
+            (3, withdraw(CLIENT, 2, Value::TWO)),
+        ])
+        .unwrap();
+        let account = eng.state.accounts.get(&CLIENT).unwrap();
+        assert_eq!(account.available(), Value::new(8, 0));
+        assert_eq!(account.held(), Value::ONE);
+        let record = eng.state.txs.get(CLIENT, 1).unwrap();
+        assert!(matches!(record.status, TxStatus::Disputed));
+        // the record keeps track of the deposit, not of the dispute
+        assert_eq!(record.seq, 1);
+    }
+
+    #[test]
+    fn test_builder_policies() {
+        let run = |builder: EngineBuilder| {
+            builder
+                .store(Backend::Memory)
+                .build()
                 .unwrap()
-                .run(batch.clone().into_iter())
+                .run(
+                    vec![
+                        deposit(CLIENT, 0, Value::TEN),
+                        withdraw(CLIENT, 1, Value::new(5, 0)),
+                        deposit(CLIENT, 2, Value::ONE),
+                        dispute(CLIENT, 0),
+                        dispute(CLIENT, 2),
+                    ]
+                    .into_iter(),
+                )
+                .unwrap()[&CLIENT]
+        };
+        let all = run(EngineBuilder::new());
+        assert_eq!(
+            (all.available(), all.held()),
+            (Value::new(-5, 0), Value::new(11, 0))
+        );
+        // the first deposit is 3 transactions away from its dispute
+        let recent = run(EngineBuilder::new().dispute_window(2));
+        assert_eq!(recent.held(), Value::ONE);
+        let covered = run(EngineBuilder::new().negative_balance(NegativeBalance::Reject));
+        assert_eq!(covered.held(), Value::ONE);
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        for builder in [
+            EngineBuilder::new().workers(0),
+            EngineBuilder::new().channel_capacity(0),
+        ] {
+            assert!(matches!(builder.build(), Err(Error::Config(_))));
+        }
+    }
+
+    #[test]
+    fn test_custom_sharding() {
+        let mut engine = EngineBuilder::new()
+            .workers(2)
+            .channel_capacity(1)
+            .sharding(Sharding::Custom(Arc::new(|_, _| 1)))
+            .store(Backend::Memory)
+            .build()
+            .unwrap();
+        for client in 0..4 {
+            engine
+                .feed(deposit(client, client as u32, Value::ONE))
+                .unwrap();
+        }
+        let metrics = engine.metrics();
+        assert_eq!(engine.finish().unwrap().len(), 4);
+        assert_eq!(metrics.worker(1).processed(), 4);
+    }
+
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<String>>);
+
+    impl EngineObserver for Arc<Recorder> {
+        fn on_applied(&self, tx: &Transaction, before: Option<&Account>, after: &Account) {
+            let before = before.map(|account| account.available());
+            let event = format!("applied {} {before:?} {}", tx.tx_id(), after.available());
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_rejected(&self, tx: &Transaction, error: &Error) {
+            let event = format!("rejected {} {}", tx.tx_id(), error.kind());
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_frozen(&self, client: Client) {
+            self.0.lock().unwrap().push(format!("frozen {client}"));
+        }
+
+        fn on_flagged(&self, tx: &Transaction, rule: &str) {
+            let event = format!("flagged {} {rule}", tx.tx_id());
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn test_observers() {
+        let (first, second) = (Arc::new(Recorder::default()), Arc::new(Recorder::default()));
+        EngineBuilder::new()
+            .workers(1)
+            .store(Backend::Memory)
+            .observer(first.clone())
+            .observer(second.clone())
+            .build()
+            .unwrap()
+            .run(
+                vec![
+                    deposit(CLIENT, 0, Value::TEN),
+                    dispute(CLIENT, 1),
+                    dispute(CLIENT, 0),
+                    chargeback(CLIENT, 0),
+                    deposit(CLIENT, 2, Value::ONE),
+                ]
+                .into_iter(),
+            )
+            .unwrap();
+        let events = first.0.lock().unwrap().clone();
+        assert_eq!(
+            events,
+            [
+                "applied 0 None 10",
+                "rejected 1 transaction_not_found",
+                "applied 0 Some(10) 0",
+                "applied 0 Some(0) 0",
+                "frozen 0",
+                "rejected 2 account_frozen",
+            ]
+        );
+        assert_eq!(*second.0.lock().unwrap(), events);
+    }
+
+    #[test]
+    fn test_fraud_freeze() {
+        let config = Config {
+            fraud: "[[rule]]\nrule = \"disputes\"\nmax = 0\naction = \"freeze\""
+                .parse()
                 .unwrap(),
-            Engine::new(8).unwrap().run(batch.into_iter()).unwrap()
+            ..Config::default()
+        };
+        let mut eng = Worker::new(0, &config, DEFAULT_CHANNEL_CAPACITY).unwrap().0;
+        // no account is opened for unknown clients
+        assert!(matches!(
+            eng.process_tx(dispute(CLIENT + 1, 0)),
+            Err(Error::Fraud("disputes"))
+        ));
+        assert!(!eng.state.accounts.contains_key(&(CLIENT + 1)));
+        eng.process_tx(deposit(CLIENT, 0, Value::TEN)).unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                eng.process_tx(dispute(CLIENT, 0)),
+                Err(Error::Fraud("disputes"))
+            ));
+        }
+        assert!(matches!(eng.state.accounts[&CLIENT], Account::Frozen(_)));
+    }
+
+    #[test]
+    fn test_fraud_rules() {
+        let rules = r#"
+            [[rule]]
+            rule = "cycling"
+            cycles = 1
+            within = 2
+            action = "flag"
+
+            [[rule]]
+            rule = "disputes"
+            max = 1
+            action = "freeze"
+        "#;
+        let recorder = Arc::new(Recorder::default());
+        let mut engine = EngineBuilder::new()
+            .workers(1)
+            .store(Backend::Memory)
+            .fraud_rules(rules.parse().unwrap())
+            .observer(recorder.clone())
+            .build()
+            .unwrap();
+        let metrics = engine.metrics();
+        for tx in [
+            deposit(CLIENT, 0, Value::TEN),
+            withdraw(CLIENT, 1, Value::ONE),
+            dispute(CLIENT, 0),
+            resolve(CLIENT, 0),
+            dispute(CLIENT, 0),
+        ] {
+            engine.feed(tx).unwrap();
+        }
+        let accounts = engine.finish().unwrap();
+        assert!(accounts[&CLIENT].is_locked());
+        assert_eq!(accounts[&CLIENT].available(), Value::new(9, 0));
+        assert_eq!(metrics.worker(0).flagged()["cycling"], 1);
+        assert_eq!(metrics.worker(0).rejected()["fraud"], 1);
+        assert_eq!(
+            recorder.0.lock().unwrap()[1..],
+            [
+                "flagged 1 cycling",
+                "applied 1 Some(10) 9",
+                "applied 0 Some(9) -1",
+                "applied 0 Some(-1) 9",
+                "rejected 0 fraud",
+                "frozen 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_observers_failed_commit() {
+        let recorder = Arc::new(Recorder::default());
+        let config = EngineBuilder::new()
+            .store(Backend::Custom(Arc::new(|_| {
+                Ok(Box::<FaultyStore>::default())
+            })))
+            .observer(recorder.clone())
+            .config;
+        let mut eng = Worker::new(0, &config, DEFAULT_CHANNEL_CAPACITY).unwrap().0;
+        assert!(eng
+            .process_batch(vec![(0, deposit(CLIENT, 0, Value::ONE))])
+            .is_err());
+        assert_eq!(*recorder.0.lock().unwrap(), ["rejected 0 store"]);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut engine = Engine::new(1).unwrap();
+        let metrics = engine.metrics();
+        engine.feed(deposit(CLIENT, 0, Value::ONE)).unwrap();
+        engine.feed(withdraw(CLIENT, 1, Value::TEN)).unwrap();
+        engine.feed(dispute(CLIENT, 5)).unwrap();
+        engine.finish().unwrap();
+
+        let worker = metrics.worker(0);
+        assert_eq!(worker.processed(), 1);
+        assert_eq!(
+            worker.rejected(),
+            std::collections::BTreeMap::from([
+                ("not_enough_funds", 1),
+                ("transaction_not_found", 1)
+            ])
+        );
+        let rendered = metrics.render();
+        assert!(rendered.contains("bcc_queue_depth{worker=\"0\"} 0\n"));
+        assert!(rendered.contains("bcc_transaction_duration_seconds_count{worker=\"0\"} 3\n"));
+        assert!(rendered.contains(
+            "bcc_store_operation_duration_seconds_count{worker=\"0\",op=\"insert\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn test_backends_agree() {
+        fn agree(batch: Vec<Transaction>) {
+            let in_memory = Config {
+                store: Backend::Memory,
+                ..Config::default()
+            };
+            let expected = Engine::with_config(2, in_memory)
+                .unwrap()
+                .run(batch.clone().into_iter())
+                .unwrap();
+            for store in [Backend::Redb(None), Backend::Log(None)] {
+                let config = Config {
+                    store,
+                    ..Config::default()
+                };
+                assert_eq!(
+                    Engine::with_config(2, config)
+                        .unwrap()
+                        .run(batch.clone().into_iter())
+                        .unwrap(),
+                    expected
+                );
+            }
+        }
+        // stores on disk take a while to set up, a few cases are enough to compare them
+        QuickCheck::new()
+            .tests(10)
+            .quickcheck(agree as fn(Vec<Transaction>));
+    }
+
+    // Backends are compared above, keep this one in memory to run all the cases quickly
+    #[quickcheck]
+    fn test_parallelism_is_correct(batch: Vec<Transaction>) {
+        let engine = |workers| {
+            EngineBuilder::new()
+                .workers(workers)
+                .store(Backend::Memory)
+                .build()
+                .unwrap()
+        };
+        assert_eq!(
+            engine(1).run(batch.clone().into_iter()).unwrap(),
+            engine(8).run(batch.into_iter()).unwrap()
         );
     }
 }
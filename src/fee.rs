@@ -0,0 +1,127 @@
+use super::common::*;
+use rust_decimal::RoundingStrategy;
+use serde::Deserialize;
+use std::path::Path;
+use thiserror::Error;
+
+/// Fees charged on top of client operations.
+///
+/// Loaded from a TOML schedule file, every section is optional and defaults to no fee:
+/// ```toml
+/// [withdrawal]
+/// flat = "0.5"
+/// percentage = "1.5"
+///
+/// [chargeback]
+/// flat = "15"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeSchedule {
+    pub withdrawal: WithdrawalFee,
+    pub chargeback: ChargebackFee,
+}
+
+/// Flat amount plus a percentage of the withdrawn amount, rounded half away from zero to
+/// the precision of amounts.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WithdrawalFee {
+    pub flat: Value,
+    pub percentage: Value,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChargebackFee {
+    pub flat: Value,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error reading fee schedule")]
+    Io(#[from] std::io::Error),
+    #[error("invalid fee schedule: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("fees cannot be negative")]
+    Negative,
+}
+
+impl FeeSchedule {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// Fee due for withdrawing `amount`, if not too large to be represented
+    pub fn withdrawal(&self, amount: Value) -> Option<Value> {
+        amount
+            .checked_mul(self.withdrawal.percentage)?
+            .checked_div(Value::ONE_HUNDRED)?
+            .round_dp_with_strategy(SCALE, RoundingStrategy::MidpointAwayFromZero)
+            .checked_add(self.withdrawal.flat)
+    }
+
+    /// Fee due for a chargeback, independently of the amount charged back
+    pub fn chargeback(&self) -> Value {
+        self.chargeback.flat
+    }
+}
+
+impl std::str::FromStr for FeeSchedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let schedule: Self = toml::from_str(s)?;
+        // a negative fee would be a credit in disguise
+        if [
+            schedule.withdrawal.flat,
+            schedule.withdrawal.percentage,
+            schedule.chargeback.flat,
+        ]
+        .iter()
+        .any(|fee| fee.is_sign_negative())
+        {
+            return Err(Error::Negative);
+        }
+        Ok(schedule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_schedule() {
+        let schedule: FeeSchedule = r#"
+            [withdrawal]
+            flat = "0.5"
+            percentage = "1.5"
+
+            [chargeback]
+            flat = "15"
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(schedule.withdrawal(Value::ONE_HUNDRED), Some(Value::new(20, 1)));
+        assert_eq!(schedule.withdrawal(Value::MAX), None);
+        // 0.0001845 of a percentage
+        assert_eq!(
+            schedule.withdrawal(Value::new(123, 4)),
+            Some(Value::new(5002, 4))
+        );
+        assert_eq!(schedule.chargeback(), Value::new(15, 0));
+    }
+
+    #[test]
+    fn test_empty_schedule() {
+        let schedule: FeeSchedule = "".parse().unwrap();
+        assert_eq!(schedule, FeeSchedule::default());
+        assert_eq!(schedule.withdrawal(Value::TEN), Some(Value::ZERO));
+    }
+
+    #[test]
+    fn test_negative_fee() {
+        assert!("[chargeback]\nflat = \"-1\"".parse::<FeeSchedule>().is_err());
+    }
+}
@@ -0,0 +1,343 @@
+use super::{account::Account, common::*, transaction::Transaction};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use thiserror::Error;
+
+/// Rules screening transactions before they are applied.
+///
+/// Loaded from a TOML file, windows count transactions of the same client:
+/// ```toml
+/// # more than 3 disputes opened by a client
+/// [[rule]]
+/// rule = "disputes"
+/// max = 3
+/// action = "freeze"
+///
+/// # withdrawing at least 90% of the available funds within 2 transactions of a deposit
+/// [[rule]]
+/// rule = "drain_after_deposit"
+/// percentage = "90"
+/// within = 2
+/// action = "reject"
+///
+/// # 3 withdrawals right after a deposit within 10 transactions
+/// [[rule]]
+/// rule = "cycling"
+/// cycles = 3
+/// within = 10
+/// action = "flag"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FraudRules {
+    #[serde(rename = "rule")]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "rule", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Rule {
+    Disputes {
+        max: u32,
+        action: Action,
+    },
+    DrainAfterDeposit {
+        percentage: Value,
+        within: u64,
+        action: Action,
+    },
+    Cycling {
+        cycles: usize,
+        within: u64,
+        action: Action,
+    },
+}
+
+/// What happens to a transaction matching a rule, from the mildest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Applied anyway, but reported
+    Flag,
+    Reject,
+    /// Rejected, and the account frozen
+    Freeze,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("error reading fraud rules")]
+    Io(#[from] std::io::Error),
+    #[error("invalid fraud rules: {0}")]
+    Parse(#[from] toml::de::Error),
+}
+
+impl FraudRules {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        std::fs::read_to_string(path)?.parse()
+    }
+}
+
+impl std::str::FromStr for FraudRules {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+impl Rule {
+    /// Name of the rule, as in the rules file
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Disputes { .. } => "disputes",
+            Self::DrainAfterDeposit { .. } => "drain_after_deposit",
+            Self::Cycling { .. } => "cycling",
+        }
+    }
+
+    pub fn action(&self) -> Action {
+        match self {
+            Self::Disputes { action, .. }
+            | Self::DrainAfterDeposit { action, .. }
+            | Self::Cycling { action, .. } => *action,
+        }
+    }
+
+    fn matches(&self, tx: &Transaction, account: Option<&Account>, activity: &Activity) -> bool {
+        match (self, tx) {
+            (Self::Disputes { max, .. }, Transaction::Dispute { .. }) => activity.disputes >= *max,
+            (
+                Self::DrainAfterDeposit {
+                    percentage, within, ..
+                },
+                Transaction::Withdrawal { value, .. },
+            ) => {
+                let available = account.map_or(Value::ZERO, Account::available);
+                activity
+                    .last_deposit
+                    .is_some_and(|at| activity.seen - at <= *within)
+                    && available.is_sign_positive()
+                    && !available.is_zero()
+                    // amounts too large to compare are left alone
+                    && value
+                        .checked_mul(Value::ONE_HUNDRED)
+                        .zip(available.checked_mul(*percentage))
+                        .is_some_and(|(drained, threshold)| drained >= threshold)
+            }
+            (Self::Cycling { cycles, within, .. }, Transaction::Withdrawal { .. }) => {
+                let recent = activity
+                    .cycles
+                    .iter()
+                    .filter(|&&at| activity.seen - at < *within)
+                    .count();
+                activity.after_deposit() && recent + 1 >= *cycles
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Rolling activity of a client, as far as rules are concerned
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Activity {
+    // transactions of the client screened so far
+    seen: u64,
+    disputes: u32,
+    // position of the last deposit among the client transactions
+    last_deposit: Option<u64>,
+    // positions of withdrawals straight after a deposit, only those which may still be
+    // within the window of a rule are kept
+    cycles: VecDeque<u64>,
+}
+
+impl Activity {
+    // whether the previous transaction of the client was a deposit
+    fn after_deposit(&self) -> bool {
+        self.seen > 0 && self.last_deposit == Some(self.seen - 1)
+    }
+}
+
+/// Rules along with the activity of the clients they have seen
+#[derive(Debug, Default)]
+pub struct Screening {
+    rules: FraudRules,
+    activity: HashMap<Client, Activity>,
+    // the largest cycling window, older cycles can be forgotten
+    max_window: u64,
+}
+
+impl Screening {
+    pub fn new(rules: FraudRules) -> Self {
+        let max_window = rules
+            .rules
+            .iter()
+            .map(|rule| match rule {
+                Rule::Cycling { within, .. } => *within,
+                _ => 0,
+            })
+            .max()
+            .unwrap_or_default();
+        Self {
+            rules,
+            activity: HashMap::new(),
+            max_window,
+        }
+    }
+
+    /// Rules matched by `tx` given the account of its client before it.
+    ///
+    /// Every transaction screened counts towards the activity of its client, whether it is
+    /// then applied or not.
+    pub fn screen(&mut self, tx: &Transaction, account: Option<&Account>) -> Vec<&Rule> {
+        if self.rules.rules.is_empty() {
+            return Vec::new();
+        }
+        let activity = self.activity.entry(tx.client()).or_default();
+        let matched = self
+            .rules
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(tx, account, activity))
+            .collect();
+
+        match tx {
+            Transaction::Deposit { .. } => activity.last_deposit = Some(activity.seen),
+            Transaction::Withdrawal { .. } if activity.after_deposit() => {
+                activity.cycles.push_back(activity.seen)
+            }
+            Transaction::Dispute { .. } => activity.disputes += 1,
+            _ => {}
+        }
+        activity.seen += 1;
+        while let Some(&at) = activity.cycles.front() {
+            if activity.seen - at <= self.max_window {
+                break;
+            }
+            activity.cycles.pop_front();
+        }
+        matched
+    }
+
+    /// Activity of `client` so far, e.g. to restore it if its transactions are discarded
+    pub fn activity(&self, client: Client) -> Option<Activity> {
+        self.activity.get(&client).cloned()
+    }
+
+    pub fn restore(&mut self, client: Client, activity: Option<Activity>) {
+        match activity {
+            Some(activity) => self.activity.insert(client, activity),
+            None => self.activity.remove(&client),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screening(rules: &str) -> Screening {
+        Screening::new(rules.parse().unwrap())
+    }
+
+    fn names(matched: Vec<&Rule>) -> Vec<&'static str> {
+        matched.into_iter().map(Rule::name).collect()
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules: FraudRules = r#"
+            [[rule]]
+            rule = "disputes"
+            max = 3
+            action = "freeze"
+
+            [[rule]]
+            rule = "drain_after_deposit"
+            percentage = "90"
+            within = 2
+            action = "reject"
+        "#
+        .parse()
+        .unwrap();
+        assert_eq!(rules.rules.len(), 2);
+        assert_eq!(rules.rules[1].action(), Action::Reject);
+        assert!("[[rule]]\nrule = \"disputes\"\nmax = 1\naction = \"ban\""
+            .parse::<FraudRules>()
+            .is_err());
+        assert_eq!("".parse::<FraudRules>().unwrap(), FraudRules::default());
+    }
+
+    #[test]
+    fn test_disputes() {
+        let mut screening = screening("[[rule]]\nrule = \"disputes\"\nmax = 1\naction = \"flag\"");
+        let dispute = Transaction::Dispute {
+            client: 1,
+            tx_id: 1,
+            value: None,
+        };
+        assert!(screening.screen(&dispute, None).is_empty());
+        assert_eq!(names(screening.screen(&dispute, None)), ["disputes"]);
+    }
+
+    #[test]
+    fn test_drain_after_deposit() {
+        let mut screening = screening(
+            "[[rule]]\nrule = \"drain_after_deposit\"\npercentage = \"90\"\nwithin = 1\naction = \"reject\"",
+        );
+        let account = Account::default().deposit(Value::TEN).unwrap();
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx_id: 1,
+            value: Value::TEN,
+        };
+        let withdrawal = |value| Transaction::Withdrawal {
+            client: 1,
+            tx_id: 2,
+            value,
+        };
+        screening.screen(&deposit, None);
+        assert!(screening
+            .screen(&withdrawal(Value::ONE), Some(&account))
+            .is_empty());
+        // out of the window already
+        assert!(screening
+            .screen(&withdrawal(Value::TEN), Some(&account))
+            .is_empty());
+        screening.screen(&deposit, None);
+        assert_eq!(
+            names(screening.screen(&withdrawal(Value::new(9, 0)), Some(&account))),
+            ["drain_after_deposit"]
+        );
+        screening.screen(&deposit, None);
+        assert!(screening
+            .screen(&withdrawal(Value::MAX), Some(&account))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_cycling() {
+        let mut screening =
+            screening("[[rule]]\nrule = \"cycling\"\ncycles = 2\nwithin = 4\naction = \"freeze\"");
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx_id: 1,
+            value: Value::ONE,
+        };
+        let withdrawal = Transaction::Withdrawal {
+            client: 1,
+            tx_id: 2,
+            value: Value::ONE,
+        };
+        screening.screen(&deposit, None);
+        assert!(screening.screen(&withdrawal, None).is_empty());
+        screening.screen(&deposit, None);
+        assert_eq!(names(screening.screen(&withdrawal, None)), ["cycling"]);
+        // the first cycles fall out of the window
+        screening.screen(&withdrawal, None);
+        screening.screen(&withdrawal, None);
+        screening.screen(&deposit, None);
+        assert!(screening.screen(&withdrawal, None).is_empty());
+    }
+}
@@ -0,0 +1,271 @@
+use super::{common::*, transaction::Transaction};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rust_decimal::prelude::ToPrimitive;
+use std::collections::HashMap;
+use thiserror::Error;
+
+// Largest deposit, in units of the smallest amount
+const MAX_DEPOSIT: i64 = 10_000 * 10_i64.pow(SCALE);
+// Attempts at picking a client which is not frozen before giving up on it
+const PICK_ATTEMPTS: usize = 100;
+
+/// Shape of a synthetic workload
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workload {
+    /// The same seed always gives the same transactions
+    pub seed: u64,
+    /// Clients are numbered from 1 to this
+    pub clients: Client,
+    /// Zipf exponent of the client distribution, 0 for uniform.
+    /// The higher, the more transactions go to the first clients.
+    pub skew: f64,
+    /// Share of transactions disputing a previous deposit, as many close a dispute
+    pub dispute_ratio: f64,
+    /// Share of closed disputes which are charged back rather than resolved
+    pub chargeback_ratio: f64,
+    /// Share of transactions withdrawing funds
+    pub withdrawal_ratio: f64,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            clients: 100,
+            skew: 1.0,
+            dispute_ratio: 0.05,
+            chargeback_ratio: 0.2,
+            withdrawal_ratio: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("at least one client is needed")]
+    NoClients,
+    #[error("{0} must be between 0 and 1")]
+    Ratio(&'static str),
+    #[error("disputes, their closing and withdrawals cannot exceed all transactions")]
+    TooManyRatios,
+    #[error("skew cannot be negative")]
+    Skew,
+}
+
+/// Endless stream of transactions following a [`Workload`].
+///
+/// Disputes, resolves and chargebacks always refer to deposits of the same client, and
+/// withdrawals never exceed what the client has available, so that only transactions of
+/// frozen clients are rejected (and only if no other client could be picked).
+pub struct Generator {
+    workload: Workload,
+    rng: ChaCha8Rng,
+    // cumulative weights of clients, to pick them by skew
+    weights: Vec<f64>,
+    clients: HashMap<Client, Activity>,
+    next_tx: TxId,
+}
+
+// What the generator knows of a client, as the engine would see it without fees
+#[derive(Debug, Default)]
+struct Activity {
+    available: Value,
+    undisputed: Vec<(TxId, Value)>,
+    disputed: Vec<(TxId, Value)>,
+    frozen: bool,
+}
+
+impl Workload {
+    pub fn generator(&self) -> Result<Generator, Error> {
+        if self.clients == 0 {
+            return Err(Error::NoClients);
+        }
+        for (name, ratio) in [
+            ("dispute ratio", self.dispute_ratio),
+            ("chargeback ratio", self.chargeback_ratio),
+            ("withdrawal ratio", self.withdrawal_ratio),
+        ] {
+            if !(0.0..=1.0).contains(&ratio) {
+                return Err(Error::Ratio(name));
+            }
+        }
+        if 2.0 * self.dispute_ratio + self.withdrawal_ratio > 1.0 {
+            return Err(Error::TooManyRatios);
+        }
+        if self.skew.is_nan() || self.skew < 0.0 {
+            return Err(Error::Skew);
+        }
+        let weights = (1..=self.clients as u32)
+            .scan(0.0, |total, rank| {
+                *total += 1.0 / f64::from(rank).powf(self.skew);
+                Some(*total)
+            })
+            .collect();
+        Ok(Generator {
+            workload: self.clone(),
+            rng: ChaCha8Rng::seed_from_u64(self.seed),
+            weights,
+            clients: HashMap::new(),
+            next_tx: 1,
+        })
+    }
+}
+
+impl Generator {
+    fn pick_client(&mut self) -> Client {
+        let mut client = 1;
+        for _ in 0..PICK_ATTEMPTS {
+            let total = self.weights.last().copied().unwrap_or_default();
+            let point = self.rng.gen_range(0.0..total);
+            // clients are numbered from 1, as ranks
+            let rank = self.weights.partition_point(|&weight| weight <= point);
+            client = rank.min(self.weights.len() - 1) as Client + 1;
+            if !self.clients.get(&client).is_some_and(|a| a.frozen) {
+                break;
+            }
+        }
+        client
+    }
+
+    fn deposit(&mut self, client: Client) -> Transaction {
+        let value = Value::new(self.rng.gen_range(1..=MAX_DEPOSIT), SCALE);
+        let tx_id = self.next_tx;
+        self.next_tx += 1;
+        let activity = self.clients.entry(client).or_default();
+        activity.available += value;
+        activity.undisputed.push((tx_id, value));
+        Transaction::Deposit {
+            client,
+            tx_id,
+            value,
+        }
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        let client = self.pick_client();
+        let roll = self.rng.gen::<f64>();
+        let chargeback = self.rng.gen_bool(self.workload.chargeback_ratio);
+        let activity = self.clients.entry(client).or_default();
+        let dispute_ratio = self.workload.dispute_ratio;
+
+        if roll < dispute_ratio && !activity.undisputed.is_empty() {
+            let i = self.rng.gen_range(0..activity.undisputed.len());
+            let (tx_id, value) = activity.undisputed.swap_remove(i);
+            activity.available -= value;
+            activity.disputed.push((tx_id, value));
+            return Some(Transaction::Dispute {
+                client,
+                tx_id,
+                value: None,
+            });
+        }
+        if roll < 2.0 * dispute_ratio && !activity.disputed.is_empty() {
+            let i = self.rng.gen_range(0..activity.disputed.len());
+            let (tx_id, value) = activity.disputed.swap_remove(i);
+            if chargeback {
+                activity.frozen = true;
+                return Some(Transaction::Chargeback {
+                    client,
+                    tx_id,
+                    value: None,
+                });
+            }
+            // resolved deposits cannot be disputed again
+            activity.available += value;
+            return Some(Transaction::Resolve {
+                client,
+                tx_id,
+                value: None,
+            });
+        }
+        let withdrawal = 2.0 * dispute_ratio + self.workload.withdrawal_ratio;
+        if roll < withdrawal && activity.available > Value::ZERO {
+            // a share of the available funds, in steps of the smallest amount
+            let units = (activity.available * Value::from(10_i64.pow(SCALE))).floor();
+            let units = units.to_i64().unwrap_or(i64::MAX).max(1);
+            let value = Value::new(self.rng.gen_range(1..=units), SCALE);
+            activity.available -= value;
+            let tx_id = self.next_tx;
+            self.next_tx += 1;
+            return Some(Transaction::Withdrawal {
+                client,
+                tx_id,
+                value,
+            });
+        }
+        Some(self.deposit(client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::EngineBuilder;
+    use crate::store::Backend;
+
+    #[test]
+    fn test_deterministic() {
+        let workload = Workload::default();
+        let first = workload.generator().unwrap().take(1000).collect::<Vec<_>>();
+        let second = workload.generator().unwrap().take(1000).collect::<Vec<_>>();
+        assert_eq!(first, second);
+        let other = Workload {
+            seed: 1,
+            ..Workload::default()
+        };
+        assert_ne!(
+            first,
+            other.generator().unwrap().take(1000).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_transactions_apply() {
+        let workload = Workload {
+            dispute_ratio: 0.2,
+            chargeback_ratio: 0.1,
+            ..Workload::default()
+        };
+        let txs = workload.generator().unwrap().take(5000).collect::<Vec<_>>();
+        assert!(txs
+            .iter()
+            .any(|tx| matches!(tx, Transaction::Chargeback { .. })));
+        let mut engine = EngineBuilder::new()
+            .workers(2)
+            .store(Backend::Memory)
+            .build()
+            .unwrap();
+        let metrics = engine.metrics();
+        for tx in txs {
+            engine.feed(tx).unwrap();
+        }
+        engine.finish().unwrap();
+        let rejected = (0..2)
+            .flat_map(|worker| metrics.worker(worker).rejected())
+            .collect::<Vec<_>>();
+        assert_eq!(rejected, []);
+    }
+
+    #[test]
+    fn test_invalid_workload() {
+        let invalid = |workload: Workload| workload.generator().is_err();
+        assert!(invalid(Workload {
+            clients: 0,
+            ..Workload::default()
+        }));
+        assert!(invalid(Workload {
+            dispute_ratio: 0.4,
+            withdrawal_ratio: 0.3,
+            ..Workload::default()
+        }));
+        assert!(invalid(Workload {
+            chargeback_ratio: 1.5,
+            ..Workload::default()
+        }));
+    }
+}
@@ -0,0 +1,204 @@
+use super::{account::Account, common::*, transaction::Transaction};
+use thiserror::Error;
+
+/// Properties of accounts which hold whatever the transactions, if `Account` is right
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invariant {
+    /// `held` is never negative
+    HeldNotNegative,
+    /// `held` is the sum of what disputes hold of the transactions of the client
+    HeldDisputed,
+    /// `total` is what was deposited, less what was withdrawn, charged back (and not
+    /// re-presented) and paid in fees
+    TotalBalanced,
+    /// `available` and `held` are the balances of the client booked in the journal
+    JournalBalanced,
+}
+
+impl Invariant {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::HeldNotNegative => "held_not_negative",
+            Self::HeldDisputed => "held_disputed",
+            Self::TotalBalanced => "total_balanced",
+            Self::JournalBalanced => "journal_balanced",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("invariant {} violated by {tx:?}: expected {expected}, found {actual}", invariant.name())]
+pub struct Violation {
+    pub invariant: Invariant,
+    /// The transaction just applied
+    pub tx: Transaction,
+    pub expected: Value,
+    pub actual: Value,
+}
+
+/// Movements of funds of a client, worked out from its transactions rather than its account
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Ledger {
+    /// Total of the account when first seen, e.g. restored from a checkpoint
+    pub opening: Value,
+    /// Held funds of the account when first seen
+    pub opening_held: Value,
+    pub deposited: Value,
+    pub withdrawn: Value,
+    pub charged_back: Value,
+    pub fees: Value,
+}
+
+impl Ledger {
+    pub fn new(account: Option<&Account>) -> Self {
+        Self {
+            opening: account.map_or(Value::ZERO, |account| account.available() + account.held()),
+            opening_held: account.map_or(Value::ZERO, Account::held),
+            ..Self::default()
+        }
+    }
+
+    /// Account for `tx`, which was applied charging `fee`.
+    /// `referenced` is the amount charged back or re-presented, if any.
+    pub fn record(&mut self, tx: &Transaction, referenced: Option<Value>, fee: Value) {
+        match tx {
+            Transaction::Deposit { value, .. } => self.deposited += value,
+            Transaction::Withdrawal { value, .. } => self.withdrawn += value,
+            Transaction::Chargeback { .. } => {
+                self.charged_back += referenced.unwrap_or_default();
+            }
+            Transaction::Representment { .. } => {
+                self.charged_back -= referenced.unwrap_or_default();
+            }
+            Transaction::Dispute { .. } | Transaction::Resolve { .. } => {}
+        }
+        self.fees += fee;
+    }
+
+    /// What the account should hold in total
+    pub fn total(&self) -> Value {
+        self.opening + self.deposited - self.withdrawn - self.charged_back - self.fees
+    }
+}
+
+/// Check `account` right after `tx` was applied to it, given the `ledger` of the client,
+/// the sum of what disputes hold of its transactions and its available and held balances
+/// booked in the journal since it was first seen.
+pub fn check(
+    tx: &Transaction,
+    account: &Account,
+    ledger: &Ledger,
+    disputed: Value,
+    booked: (Value, Value),
+) -> Result<(), Violation> {
+    let violation = |invariant, expected, actual| Violation {
+        invariant,
+        tx: tx.clone(),
+        expected,
+        actual,
+    };
+    let held = account.held();
+    if held.is_sign_negative() && !held.is_zero() {
+        return Err(violation(Invariant::HeldNotNegative, Value::ZERO, held));
+    }
+    if held != disputed {
+        return Err(violation(Invariant::HeldDisputed, disputed, held));
+    }
+    let total = account.available() + held;
+    if total != ledger.total() {
+        return Err(violation(Invariant::TotalBalanced, ledger.total(), total));
+    }
+    // the journal starts from the balances the account was first seen with
+    let (available, held) = booked;
+    for (expected, actual) in [
+        (
+            ledger.opening - ledger.opening_held + available,
+            account.available(),
+        ),
+        (ledger.opening_held + held, account.held()),
+    ] {
+        if expected != actual {
+            return Err(violation(Invariant::JournalBalanced, expected, actual));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx_id: 1,
+            value: Value::TEN,
+        };
+        let chargeback = Transaction::Chargeback {
+            client: 1,
+            tx_id: 1,
+            value: None,
+        };
+        let mut ledger = Ledger::new(None);
+        ledger.record(&deposit, None, Value::ZERO);
+        let deposited = Account::default().deposit(Value::TEN).unwrap();
+        let booked = (Value::TEN, Value::ZERO);
+        assert_eq!(
+            check(&deposit, &deposited, &ledger, Value::ZERO, booked),
+            Ok(())
+        );
+        let violation = check(
+            &deposit,
+            &deposited,
+            &ledger,
+            Value::ZERO,
+            Default::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            (violation.invariant, violation.expected, violation.actual),
+            (Invariant::JournalBalanced, Value::ZERO, Value::TEN)
+        );
+
+        let disputed = deposited.freeze_funds(Value::TEN).unwrap();
+        let booked = (Value::ZERO, Value::TEN);
+        let violation = check(&deposit, &disputed, &ledger, Value::ZERO, booked).unwrap_err();
+        assert_eq!(
+            (violation.invariant, violation.expected, violation.actual),
+            (Invariant::HeldDisputed, Value::ZERO, Value::TEN)
+        );
+        assert_eq!(
+            check(&deposit, &disputed, &ledger, Value::TEN, booked),
+            Ok(())
+        );
+
+        let charged_back = disputed.chargeback(Value::TEN, Value::ONE).unwrap();
+        let booked = (-Value::ONE, Value::ZERO);
+        let violation =
+            check(&chargeback, &charged_back, &ledger, Value::ZERO, booked).unwrap_err();
+        assert_eq!(violation.invariant, Invariant::TotalBalanced);
+        assert_eq!(violation.tx, chargeback);
+        ledger.record(&chargeback, Some(Value::TEN), Value::ONE);
+        assert_eq!(
+            check(&chargeback, &charged_back, &ledger, Value::ZERO, booked),
+            Ok(())
+        );
+
+        // restored accounts open with their balance
+        let ledger = Ledger::new(Some(&charged_back));
+        assert_eq!(ledger.total(), -Value::ONE);
+
+        let broken: Account =
+            toml::from_str("[Active]\navailable = \"2\"\nheld = \"-1\"\nfees = \"0\"").unwrap();
+        let violation = check(
+            &deposit,
+            &broken,
+            &Ledger::new(None),
+            Value::ZERO,
+            Default::default(),
+        )
+        .unwrap_err();
+        assert_eq!(violation.invariant, Invariant::HeldNotNegative);
+    }
+}
@@ -0,0 +1,207 @@
+use super::common::*;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Where funds are booked in the [`Journal`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LedgerAccount {
+    /// Funds a client can use
+    Available(Client),
+    /// Funds of a client held by disputes
+    Held(Client),
+    /// Funds coming from and going to the outside world, i.e. deposits and withdrawals
+    Settlement,
+    /// Funds taken back from clients by chargebacks
+    ChargebackLoss,
+    /// Fees charged to clients
+    Fees,
+}
+
+impl std::fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Available(client) => write!(f, "client:{client}:available"),
+            Self::Held(client) => write!(f, "client:{client}:held"),
+            Self::Settlement => f.write_str("settlement"),
+            Self::ChargebackLoss => f.write_str("chargeback_loss"),
+            Self::Fees => f.write_str("fees"),
+        }
+    }
+}
+
+impl Serialize for LedgerAccount {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// `amount` moved from the `debit` account to the `credit` one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Posting {
+    /// Position in the input of the transaction
+    pub seq: u64,
+    pub tx: TxId,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: Value,
+}
+
+/// Double-entry record of the funds moved by transactions.
+///
+/// Every operation on an account is booked as balanced postings, e.g. a dispute debits the
+/// available funds of the client and credits its held ones. Balances are credits less debits,
+/// so that funds of clients are positive and the sum of all balances is always zero.
+///
+/// Postings are kept in memory, one to three for each applied transaction, unless
+/// only balances are kept.
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    postings: Vec<Posting>,
+    balances: HashMap<LedgerAccount, Value>,
+    // postings are dropped once settled
+    balances_only: bool,
+}
+
+impl Journal {
+    /// A journal keeping postings only until they are settled, memory stays bounded by the
+    /// amount of ledger accounts
+    pub fn balances_only() -> Self {
+        Self {
+            balances_only: true,
+            ..Self::default()
+        }
+    }
+
+    /// Book a posting, postings of nothing (e.g. no fees) are left out
+    pub fn post(&mut self, posting: Posting) {
+        if posting.amount.is_zero() {
+            return;
+        }
+        self.apply(&posting, Value::ONE);
+        self.postings.push(posting);
+    }
+
+    fn apply(&mut self, posting: &Posting, sign: Value) {
+        *self.balances.entry(posting.debit).or_default() -= posting.amount * sign;
+        *self.balances.entry(posting.credit).or_default() += posting.amount * sign;
+    }
+
+    pub fn balance(&self, account: LedgerAccount) -> Value {
+        self.balances.get(&account).copied().unwrap_or_default()
+    }
+
+    /// Available and held funds of `client`, as derived from postings
+    pub fn client(&self, client: Client) -> (Value, Value) {
+        (
+            self.balance(LedgerAccount::Available(client)),
+            self.balance(LedgerAccount::Held(client)),
+        )
+    }
+
+    /// Balances of all accounts, ordered by account
+    pub fn balances(&self) -> Vec<(LedgerAccount, Value)> {
+        let mut balances = self
+            .balances
+            .iter()
+            .map(|(account, balance)| (*account, *balance))
+            .collect::<Vec<_>>();
+        balances.sort();
+        balances
+    }
+
+    /// Sum of all balances, zero unless something is badly wrong
+    pub fn sum(&self) -> Value {
+        self.balances.values().sum()
+    }
+
+    pub fn postings(&self) -> &[Posting] {
+        &self.postings
+    }
+
+    pub fn len(&self) -> usize {
+        self.postings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.postings.is_empty()
+    }
+
+    /// Drop the postings after the first `len`, e.g. those of a batch which was not committed
+    pub fn truncate(&mut self, len: usize) {
+        for posting in self.postings.split_off(len.min(self.postings.len())) {
+            self.apply(&posting, Value::NEGATIVE_ONE);
+        }
+    }
+
+    /// Postings so far will not be truncated, those of a journal keeping balances only are dropped
+    pub fn settle(&mut self) {
+        if self.balances_only {
+            self.postings.clear();
+        }
+    }
+
+    /// Add the postings of `other`, keeping them all in input order
+    pub fn merge(&mut self, other: Journal) {
+        for posting in other.postings {
+            self.post(posting);
+        }
+        // stable, postings of the same transaction stay in order
+        self.postings.sort_by_key(|posting| posting.seq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use LedgerAccount::*;
+
+    fn posting(seq: u64, debit: LedgerAccount, credit: LedgerAccount, amount: Value) -> Posting {
+        Posting {
+            seq,
+            tx: seq as TxId,
+            debit,
+            credit,
+            amount,
+        }
+    }
+
+    #[test]
+    fn test_journal() {
+        let mut journal = Journal::default();
+        journal.post(posting(0, Settlement, Available(1), Value::TEN));
+        journal.post(posting(1, Available(1), Held(1), Value::TWO));
+        journal.post(posting(1, Available(1), Fees, Value::ZERO));
+        assert_eq!(journal.len(), 2);
+        assert_eq!(journal.client(1), (Value::new(8, 0), Value::TWO));
+        assert_eq!(journal.balance(Settlement), -Value::TEN);
+        assert_eq!(journal.sum(), Value::ZERO);
+
+        journal.truncate(1);
+        assert_eq!(journal.client(1), (Value::TEN, Value::ZERO));
+        assert_eq!(journal.balance(Held(1)), Value::ZERO);
+
+        let mut other = Journal::default();
+        other.post(posting(1, Settlement, Available(2), Value::ONE));
+        other.post(posting(2, Held(2), ChargebackLoss, Value::ONE));
+        journal.merge(other);
+        assert_eq!(
+            journal
+                .postings()
+                .iter()
+                .map(|posting| posting.seq)
+                .collect::<Vec<_>>(),
+            [0, 1, 2]
+        );
+        assert_eq!(journal.sum(), Value::ZERO);
+        assert_eq!(journal.balances()[0], (Available(1), Value::TEN));
+        assert_eq!(Available(1).to_string(), "client:1:available");
+
+        let mut balances = Journal::balances_only();
+        balances.post(posting(0, Settlement, Available(1), Value::TEN));
+        balances.settle();
+        balances.post(posting(1, Available(1), Held(1), Value::TWO));
+        balances.truncate(0);
+        assert!(balances.is_empty());
+        assert_eq!(balances.client(1), (Value::TEN, Value::ZERO));
+    }
+}
@@ -1,5 +1,14 @@
 pub mod account;
+pub mod checkpoint;
 pub mod common;
 pub mod engine;
-mod store;
+pub mod fee;
+pub mod fraud;
+pub mod generate;
+pub mod invariant;
+pub mod ledger;
+pub mod metrics;
+pub mod observer;
+pub mod report;
+pub mod store;
 pub mod transaction;
@@ -1,9 +1,17 @@
 use bcc::account::Account;
+use bcc::checkpoint::{self, Checkpoint};
 use bcc::common::*;
-use bcc::engine::{self, Accounts};
-use bcc::transaction::{serde::TransactionCompatCsv, Transaction};
-use clap::Parser;
-use std::path::PathBuf;
+use bcc::engine::{self, Accounts, Engine, NegativeBalance, Redispute};
+use bcc::fee::{self, FeeSchedule};
+use bcc::fraud::{self, FraudRules};
+use bcc::generate::{self, Workload};
+use bcc::report::{self, Aggregates, ClientStats};
+use bcc::store::{self, Backend, Stats, TransactionStore, TxStore};
+use bcc::transaction::{serde::TransactionCompatCsv, Transaction, TxStatus};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use thiserror::Error;
 
 /// Assumptions made in the assignment:
@@ -13,11 +21,111 @@ use thiserror::Error;
 /// * deposit and withdrawal amounts are non negative
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Process transactions when no command is given
+    #[command(flatten)]
+    run: Cmd,
+    /// Log filter, either a level or a list of `target=level` directives
+    #[arg(long, global = true, default_value = "warn")]
+    log_level: String,
+    /// Format of the logs written to stderr
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the stored transactions of a client
+    History(HistoryCmd),
+    /// Inspect or maintain a persisted transaction store
+    #[command(subcommand)]
+    Store(StoreCmd),
+    /// Compare two accounts files, e.g. an output with balances from another system
+    Diff(DiffCmd),
+    /// Process transactions and print the activity of each client instead of balances,
+    /// followed by the totals of all clients
+    Report(Box<Cmd>),
+    /// Write a synthetic workload of transactions, e.g. for load tests or demos
+    Generate(GenerateCmd),
+}
+
+#[derive(Args)]
 struct Cmd {
     /// Input file for transactions
-    path: PathBuf,
+    // Only optional so that it can be omitted when running subcommands
+    #[arg(required = true)]
+    path: Option<PathBuf>,
     /// Output file for accounts, defaults to stdio
     output_file: Option<PathBuf>,
+    /// Fee schedule (TOML) for withdrawals and chargebacks, no fees are charged if missing
+    #[arg(long)]
+    fees: Option<PathBuf>,
+    /// Directory to persist the transaction store in, a temporary one is used if missing.
+    /// Must be empty unless resuming from `--checkpoint`
+    #[arg(long)]
+    store: Option<PathBuf>,
+    /// Keep transactions in memory instead, faster for inputs that fit in memory
+    #[arg(long, conflicts_with = "store")]
+    in_memory: bool,
+    /// Memory budget in bytes per worker for recent transactions, older ones are kept on disk
+    #[arg(long, conflicts_with = "in_memory")]
+    hot_tier: Option<usize>,
+    /// Serve Prometheus metrics over HTTP on this address while running, e.g. 127.0.0.1:9100
+    #[arg(long)]
+    metrics_addr: Option<String>,
+    /// Write Prometheus metrics to this file once done
+    #[arg(long)]
+    metrics_file: Option<PathBuf>,
+    /// Fraud rules (TOML) to screen transactions with, none if missing
+    #[arg(long)]
+    fraud_rules: Option<PathBuf>,
+    /// Worker threads processing transactions, one per CPU by default
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    workers: Option<usize>,
+    /// Reject disputes coming more than this many rows after the disputed deposit
+    #[arg(long)]
+    dispute_window: Option<u64>,
+    /// Reject representments coming more than this many rows after the chargeback
+    #[arg(long)]
+    representment_window: Option<u64>,
+    /// Reject disputes which would take the available balance below zero
+    #[arg(long)]
+    reject_negative_balance: bool,
+    /// Allow disputing again funds released by resolved disputes
+    #[arg(long)]
+    allow_redispute: bool,
+    /// Check ledger invariants after every applied transaction, failing on the first violation
+    /// along with the transaction causing it. Much slower, meant to catch bugs on real data
+    #[arg(long)]
+    check_invariants: bool,
+    /// Write the double-entry postings of all applied transactions to this file (CSV),
+    /// in input order. Postings are kept in memory until done
+    #[arg(long)]
+    ledger: Option<PathBuf>,
+    /// Save progress to this file once done or interrupted, and resume from it if it exists.
+    /// Use with `--store` for disputes to find transactions processed before resuming
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct HistoryCmd {
+    /// Client to print the transactions of
+    #[arg(long)]
+    client: Client,
+    /// Directory the transaction store was persisted in
+    #[arg(long)]
+    store: PathBuf,
 }
 
 #[derive(Debug, Error)]
@@ -28,70 +136,575 @@ enum Error {
     Io(#[from] std::io::Error),
     #[error(transparent)]
     Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Fee(#[from] fee::Error),
+    #[error(transparent)]
+    Fraud(#[from] fraud::Error),
+    #[error(transparent)]
+    Generate(#[from] generate::Error),
+    #[error(transparent)]
+    Store(#[from] store::Error),
+    #[error("found {0} problems in the store")]
+    Check(usize),
+    #[error("found {0} differences")]
+    Diff(usize),
+    #[error(transparent)]
+    Checkpoint(#[from] checkpoint::Error),
+    #[error("cannot handle signals: {0}")]
+    Signal(#[from] ctrlc::Error),
+    #[error("interrupted, rows up to {0} were applied")]
+    Interrupted(u64),
+    #[error("activity adds up to {activity}, but accounts hold {accounts} in total")]
+    Unreconciled { activity: Value, accounts: Value },
+    #[error("invalid log level: {0}")]
+    Log(#[from] tracing_subscriber::filter::ParseError),
+    #[error("store {0} already holds transactions of another run, use an empty directory or resume it with --checkpoint")]
+    StoreNotEmpty(PathBuf),
+}
+
+// Set on SIGINT/SIGTERM, input is no longer read after that
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Args)]
+struct DiffCmd {
+    left: PathBuf,
+    right: PathBuf,
+    /// Largest difference between amounts still considered equal
+    #[arg(long, default_value = "0")]
+    tolerance: Value,
+}
+
+impl DiffCmd {
+    fn exec(self) -> Result<(), Error> {
+        let mut writer = csv::Writer::from_writer(std::io::stdout());
+        let differences = diff(&self.left, &self.right, self.tolerance, &mut writer)?;
+        writer.flush()?;
+        match differences {
+            0 => Ok(()),
+            n => Err(Error::Diff(n)),
+        }
+    }
+}
+
+#[derive(Args)]
+struct GenerateCmd {
+    /// Output file for transactions, defaults to stdio
+    output_file: Option<PathBuf>,
+    /// Number of transactions to generate
+    #[arg(long, default_value_t = 1000)]
+    transactions: usize,
+    /// The same seed always generates the same transactions
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    #[arg(long, default_value_t = 100)]
+    clients: Client,
+    /// Zipf exponent of the client distribution, 0 for uniform
+    #[arg(long, default_value_t = 1.0)]
+    skew: f64,
+    /// Share of transactions disputing a previous deposit, as many resolve or charge one back
+    #[arg(long, default_value_t = 0.05)]
+    dispute_ratio: f64,
+    /// Share of closed disputes which are charged back
+    #[arg(long, default_value_t = 0.2)]
+    chargeback_ratio: f64,
+    /// Share of transactions withdrawing funds
+    #[arg(long, default_value_t = 0.3)]
+    withdrawal_ratio: f64,
+}
+
+impl GenerateCmd {
+    fn exec(self) -> Result<(), Error> {
+        let generator = Workload {
+            seed: self.seed,
+            clients: self.clients,
+            skew: self.skew,
+            dispute_ratio: self.dispute_ratio,
+            chargeback_ratio: self.chargeback_ratio,
+            withdrawal_ratio: self.withdrawal_ratio,
+        }
+        .generator()?;
+        let output: Box<dyn std::io::Write> = match &self.output_file {
+            Some(path) => Box::new(std::fs::File::create(path)?),
+            None => Box::new(std::io::stdout()),
+        };
+        let mut writer = csv::Writer::from_writer(output);
+        for tx in generator.take(self.transactions) {
+            writer.serialize(TransactionCompatCsv::from(&tx))?;
+        }
+        Ok(writer.flush()?)
+    }
+}
+
+// What a run writes out once done
+#[derive(Clone, Copy)]
+enum Output {
+    Accounts,
+    Report,
 }
 
 impl Cmd {
-    // This is sync for now since we only have to read from one file but can be turned into async rather easily
     fn exec(self) -> Result<(), Error> {
+        self.run(Output::Accounts)
+    }
+
+    // This is sync for now since we only have to read from one file but can be turned into async rather easily
+    fn run(self, output: Output) -> Result<(), Error> {
         let records = csv::ReaderBuilder::new()
             .trim(csv::Trim::All)
             .flexible(true)
-            .from_path(self.path)?
+            .from_path(self.path.expect("required by clap"))?
             .into_deserialize::<TransactionCompatCsv>()
             .map(|maybe_tx| Ok::<_, Error>(Transaction::try_from(maybe_tx?)?));
 
-        let mut engine = engine::Engine::new(num_cpus::get())?;
-        for tx in records {
-            engine.feed(tx?)?;
+        let fees = match self.fees {
+            Some(path) => FeeSchedule::from_path(path)?,
+            None => FeeSchedule::default(),
+        };
+        let resuming = self.checkpoint.as_ref().is_some_and(|path| path.exists());
+        // shards are reopened as they are, a fresh run would mix in transactions of earlier ones
+        if let Some(dir) = self.store.as_ref().filter(|_| !resuming) {
+            if dir.exists() && std::fs::read_dir(dir)?.next().is_some() {
+                return Err(Error::StoreNotEmpty(dir.clone()));
+            }
+        }
+        let store = match (self.in_memory, self.hot_tier) {
+            (true, _) => Backend::Memory,
+            (false, None) => Backend::Redb(self.store),
+            (false, Some(budget)) => Backend::Tiered {
+                budget,
+                cold: Box::new(Backend::Redb(self.store)),
+            },
+        };
+        let mut builder = Engine::builder()
+            .fees(fees)
+            .store(store)
+            .negative_balance(match self.reject_negative_balance {
+                true => NegativeBalance::Reject,
+                false => NegativeBalance::Allow,
+            })
+            .redispute(match self.allow_redispute {
+                true => Redispute::Allow,
+                false => Redispute::Reject,
+            })
+            .check_invariants(self.check_invariants)
+            .ledger(self.ledger.is_some());
+        if let Some(path) = self.fraud_rules {
+            builder = builder.fraud_rules(FraudRules::from_path(path)?);
+        }
+        if let Some(workers) = self.workers {
+            builder = builder.workers(workers);
+        }
+        if let Some(window) = self.dispute_window {
+            builder = builder.dispute_window(window);
+        }
+        if let Some(window) = self.representment_window {
+            builder = builder.representment_window(window);
+        }
+        let mut engine = builder.build()?;
+        let metrics = engine.metrics();
+        if let Some(addr) = self.metrics_addr {
+            metrics.serve(addr)?;
+        }
+        let mut skip = 0;
+        if let Some(path) = self.checkpoint.as_ref().filter(|_| resuming) {
+            let checkpoint = Checkpoint::from_path(path)?;
+            tracing::info!(last_row = checkpoint.last_row, "resuming from checkpoint");
+            engine.resume(&checkpoint)?;
+            skip = checkpoint.last_row as usize;
+        }
+        let mut interrupted = false;
+        let mut failed = None;
+        for tx in records.skip(skip) {
+            if INTERRUPTED.load(Ordering::Relaxed) {
+                tracing::warn!("interrupted, processing transactions already queued");
+                interrupted = true;
+                break;
+            }
+            // keep what healthy workers did so far if one fails
+            if let Err(e) = engine.feed(tx?) {
+                failed = Some(e);
+                break;
+            }
+        }
+
+        // every transaction fed is applied once the engine is done
+        let last_row = engine.position();
+        let workers = engine.workers();
+        let finished = engine.finish_partial();
+        for failure in &finished.failures {
+            tracing::error!(error = %failure, "only the accounts of healthy workers are written");
+        }
+        let failed = failed.or(finished.failures.into_iter().next());
+        // the checkpoint would miss the accounts of failed workers
+        if let (Some(path), None) = (self.checkpoint, &failed) {
+            Checkpoint::new(last_row, workers, &finished.accounts).write_to(path)?;
+        }
+        if let Some(path) = self.metrics_file {
+            metrics.write_to(path)?;
+        }
+        if let Some(path) = self.ledger {
+            let mut writer = csv::Writer::from_path(path)?;
+            for posting in finished.journal.postings() {
+                writer.serialize(posting)?;
+            }
+            writer.flush()?;
+        }
+        let mut writer: Box<dyn std::io::Write> = match self.output_file {
+            Some(path) => Box::new(std::fs::File::create(path)?),
+            None => Box::new(std::io::stdout()),
+        };
+        let mut unreconciled = None;
+        match output {
+            Output::Accounts => write_state_to_csv(finished.accounts, &mut writer)?,
+            Output::Report => {
+                let (activity, accounts) =
+                    write_report_to_csv(&finished.accounts, &finished.stats, &mut writer)?;
+                // activity before the checkpoint is not known
+                if activity != accounts && skip == 0 {
+                    unreconciled = Some((activity, accounts));
+                }
+            }
+        }
+        // readers of the output can tell where to resume from
+        if interrupted {
+            writeln!(writer, "# last_row = {last_row}")?;
+        }
+        match (failed, interrupted, unreconciled) {
+            (Some(e), ..) => Err(e.into()),
+            (None, true, _) => Err(Error::Interrupted(last_row)),
+            (None, false, Some((activity, accounts))) => {
+                Err(Error::Unreconciled { activity, accounts })
+            }
+            (None, false, None) => Ok(()),
         }
+    }
+}
+
+#[derive(Subcommand)]
+enum StoreCmd {
+    /// Print record counts and size of the store
+    Stats {
+        /// Directory the transaction store was persisted in
+        #[arg(long)]
+        store: PathBuf,
+        /// Print the amount of records per client instead
+        #[arg(long)]
+        clients: bool,
+    },
+    /// Reclaim space left by removed records
+    Compact {
+        /// Directory the transaction store was persisted in
+        #[arg(long)]
+        store: PathBuf,
+    },
+    /// Look for records which cannot be read and for held funds not matching disputes
+    Check {
+        /// Directory the transaction store was persisted in
+        #[arg(long)]
+        store: PathBuf,
+        /// Accounts output of the run which persisted the store, to cross-check held funds with
+        #[arg(long)]
+        accounts: Option<PathBuf>,
+        /// Move records which cannot be read to a quarantine table
+        #[arg(long)]
+        repair: bool,
+    },
+}
 
-        let state = engine.finish()?;
-        if let Some(filepath) = self.output_file {
-            Ok(write_state_to_csv(
-                state,
-                std::fs::File::create(&filepath)?,
-            )?)
+impl StoreCmd {
+    fn exec(self) -> Result<(), Error> {
+        let mut writer = csv::Writer::from_writer(std::io::stdout());
+        match self {
+            StoreCmd::Stats { store, clients } => {
+                let mut stats = Stats::default();
+                for (_, shard) in TransactionStore::open_shards(store)? {
+                    stats.merge(shard.stats()?);
+                }
+                if clients {
+                    writer.write_record(["client", "records"])?;
+                    for (client, records) in stats.clients {
+                        writer.serialize((client, records))?;
+                    }
+                } else {
+                    writer.serialize(stats)?;
+                }
+            }
+            StoreCmd::Compact { store } => {
+                writer.write_record(["shard", "bytes_before", "bytes_after"])?;
+                for (id, mut shard) in TransactionStore::open_shards(store)? {
+                    let before = shard.stats()?.bytes_on_disk;
+                    shard.compact()?;
+                    writer.serialize((id, before, shard.stats()?.bytes_on_disk))?;
+                }
+            }
+            StoreCmd::Check {
+                store,
+                accounts,
+                repair,
+            } => {
+                let problems = check(&store, accounts, repair, &mut writer)?;
+                writer.flush()?;
+                if problems > 0 {
+                    return Err(Error::Check(problems));
+                }
+            }
+        }
+        Ok(writer.flush()?)
+    }
+}
+
+// Report problems found in the store at `dir`, returns how many are left
+fn check<W: std::io::Write>(
+    dir: &Path,
+    accounts: Option<PathBuf>,
+    repair: bool,
+    writer: &mut csv::Writer<W>,
+) -> Result<usize, Error> {
+    #[derive(serde::Serialize)]
+    struct Problem {
+        shard: Option<usize>,
+        client: Client,
+        tx: Option<TxId>,
+        problem: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Held {
+        client: Client,
+        held: Value,
+    }
+
+    let mut problems = 0;
+    // disputed funds per client, along with the shard the client is in
+    let mut disputed = BTreeMap::new();
+    for (id, mut shard) in TransactionStore::open_shards(dir)? {
+        let check = shard.check()?;
+        for (client, tx, e) in &check.corrupted {
+            writer.serialize(Problem {
+                shard: Some(id),
+                client: *client,
+                tx: Some(*tx),
+                problem: match repair {
+                    true => format!("{e}, quarantined"),
+                    false => e.to_string(),
+                },
+            })?;
+        }
+        if repair {
+            let corrupted = check
+                .corrupted
+                .iter()
+                .map(|(client, tx, _)| (*client, *tx))
+                .collect::<Vec<_>>();
+            shard.quarantine(&corrupted)?;
         } else {
-            Ok(write_state_to_csv(state, std::io::stdout())?)
+            problems += check.corrupted.len();
+        }
+        disputed.extend(check.held.into_iter().map(|(client, v)| (client, (id, v))));
+    }
+
+    let Some(accounts) = accounts else {
+        return Ok(problems);
+    };
+    let mut held = BTreeMap::new();
+    for account in csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(accounts)?
+        .into_deserialize::<Held>()
+    {
+        let account = account?;
+        held.insert(account.client, account.held);
+    }
+    let clients = held
+        .keys()
+        .chain(disputed.keys())
+        .copied()
+        .collect::<BTreeSet<_>>();
+    for client in clients {
+        let (shard, disputed) = disputed
+            .get(&client)
+            .map_or((None, Value::ZERO), |(shard, value)| (Some(*shard), *value));
+        let held = held.get(&client).copied().unwrap_or_default();
+        if held != disputed {
+            problems += 1;
+            writer.serialize(Problem {
+                shard,
+                client,
+                tx: None,
+                problem: format!("{held} held but {disputed} disputed"),
+            })?;
+        }
+    }
+    Ok(problems)
+}
+
+impl HistoryCmd {
+    fn exec(self) -> Result<(), Error> {
+        #[derive(serde::Serialize)]
+        struct Record {
+            client: Client,
+            tx: TxId,
+            amount: Value,
+            status: TxStatus,
+        }
+
+        // clients live in a single shard, but which one depends on the amount of workers used
+        let mut history = Vec::new();
+        for (_, shard) in TransactionStore::open_shards(&self.store)? {
+            history.extend(shard.client_history(self.client)?);
         }
+        history.sort_by_key(|(tx, _)| *tx);
+
+        let mut writer = csv::Writer::from_writer(std::io::stdout());
+        for (tx, record) in history {
+            writer.serialize(Record {
+                client: self.client,
+                tx,
+                amount: record.value,
+                status: record.status,
+            })?;
+        }
+        Ok(writer.flush()?)
     }
 }
 
 fn main() -> Result<(), Error> {
-    Cmd::parse().exec()
+    let cli = Cli::parse();
+    init_logging(&cli.log_level, cli.log_format)?;
+    match cli.command {
+        Some(Command::History(cmd)) => cmd.exec(),
+        Some(Command::Store(cmd)) => cmd.exec(),
+        Some(Command::Report(cmd)) => {
+            handle_signals()?;
+            cmd.run(Output::Report)
+        }
+        Some(Command::Diff(cmd)) => cmd.exec(),
+        Some(Command::Generate(cmd)) => cmd.exec(),
+        None => {
+            handle_signals()?;
+            cli.run.exec()
+        }
+    }
 }
 
-fn write_state_to_csv<W: std::io::Write>(accounts: Accounts, writer: W) -> std::io::Result<()> {
+// Only runs stop reading input on a signal, other commands keep the default behaviour.
+// A second signal gives up on finishing gracefully
+fn handle_signals() -> Result<(), Error> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+    })?;
+    Ok(())
+}
+
+fn init_logging(level: &str, format: LogFormat) -> Result<(), Error> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::try_new(level)?)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+    Ok(())
+}
+
+// Returns the balance of all clients according to their activity, and the total of their
+// accounts, which should match
+fn write_report_to_csv<W: std::io::Write>(
+    accounts: &Accounts,
+    stats: &Aggregates,
+    writer: W,
+) -> Result<(Value, Value), Error> {
     #[derive(serde::Serialize)]
     struct Record {
-        client: Client,
-        available: Value,
-        held: Value,
+        client: String,
+        deposited: Value,
+        withdrawn: Value,
+        disputed: Value,
+        resolved: Value,
+        charged_back: Value,
+        represented: Value,
+        fees: Value,
+        deposits: u64,
+        withdrawals: u64,
+        disputes: u64,
+        resolves: u64,
+        chargebacks: u64,
+        representments: u64,
+        rejected: u64,
         total: Value,
-        locked: bool,
     }
 
-    impl From<(Client, Account)> for Record {
-        fn from(from: (Client, Account)) -> Record {
-            match from {
-                (client, Account::Active(inner)) => Record {
-                    client,
-                    available: inner.available,
-                    held: inner.held,
-                    total: inner.available + inner.held,
-                    locked: false,
-                },
-                (client, Account::Frozen(inner)) => Record {
-                    client,
-                    available: inner.available,
-                    held: inner.held,
-                    total: inner.available + inner.held,
-                    locked: true,
-                },
+    impl Record {
+        fn new(client: String, stats: &ClientStats, total: Value) -> Self {
+            Record {
+                client,
+                deposited: stats.deposited,
+                withdrawn: stats.withdrawn,
+                disputed: stats.disputed,
+                resolved: stats.resolved,
+                charged_back: stats.charged_back,
+                represented: stats.represented,
+                fees: stats.fees,
+                deposits: stats.deposits,
+                withdrawals: stats.withdrawals,
+                disputes: stats.disputes,
+                resolves: stats.resolves,
+                chargebacks: stats.chargebacks,
+                representments: stats.representments,
+                rejected: stats.rejected,
+                total,
             }
         }
     }
 
+    let total = |client| {
+        accounts
+            .get(client)
+            .map_or(Value::ZERO, |account| account.available() + account.held())
+    };
+    let mut writer = csv::Writer::from_writer(writer);
+    for (client, client_stats) in stats.iter().collect::<BTreeMap<_, _>>() {
+        writer.serialize(Record::new(client.to_string(), client_stats, total(client)))?;
+    }
+    let totals = report::totals(stats.values());
+    let accounts_total = accounts.keys().map(total).sum();
+    writer.serialize(Record::new("all".to_string(), &totals, accounts_total))?;
+    writer.flush()?;
+    Ok((totals.balance(), accounts_total))
+}
+
+/// An account as written out, and read back to compare outputs
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Record {
+    client: Client,
+    available: Value,
+    held: Value,
+    total: Value,
+    locked: bool,
+    // not in the outputs of other systems
+    #[serde(default)]
+    fees: Option<Value>,
+    #[serde(default)]
+    state: Option<String>,
+}
+
+impl From<(Client, Account)> for Record {
+    fn from((client, account): (Client, Account)) -> Record {
+        Record {
+            client,
+            available: account.available(),
+            held: account.held(),
+            total: account.available() + account.held(),
+            locked: account.is_locked(),
+            fees: Some(account.fees()),
+            state: Some(account.state().to_string()),
+        }
+    }
+}
+
+fn write_state_to_csv<W: std::io::Write>(accounts: Accounts, writer: W) -> std::io::Result<()> {
     let mut writer = csv::Writer::from_writer(writer);
     for record in accounts.into_iter() {
         writer.serialize(Record::from(record))?;
@@ -99,6 +712,104 @@ fn write_state_to_csv<W: std::io::Write>(accounts: Accounts, writer: W) -> std::
     Ok(())
 }
 
+// Report the differences between two accounts files, returns how many were found
+fn diff<W: std::io::Write>(
+    left: &Path,
+    right: &Path,
+    tolerance: Value,
+    writer: &mut csv::Writer<W>,
+) -> Result<usize, Error> {
+    #[derive(serde::Serialize)]
+    struct Difference {
+        client: Client,
+        field: &'static str,
+        left: String,
+        right: String,
+        // right minus left, for amounts
+        delta: Option<Value>,
+    }
+
+    let read = |path: &Path| -> Result<BTreeMap<Client, Record>, Error> {
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .comment(Some(b'#'))
+            .from_path(path)?
+            .into_deserialize::<Record>()
+            .map(|record| {
+                let record = record?;
+                Ok((record.client, record))
+            })
+            .collect()
+    };
+    let (left, right) = (read(left)?, read(right)?);
+    let clients = left.keys().chain(right.keys()).collect::<BTreeSet<_>>();
+
+    let mut differences = Vec::new();
+    for &client in clients {
+        let (left, right) = match (left.get(&client), right.get(&client)) {
+            (Some(left), Some(right)) => (left, right),
+            (left, _) => {
+                let presence = |present| match present {
+                    true => "present".to_string(),
+                    false => "missing".to_string(),
+                };
+                differences.push(Difference {
+                    client,
+                    field: "client",
+                    left: presence(left.is_some()),
+                    right: presence(left.is_none()),
+                    delta: None,
+                });
+                continue;
+            }
+        };
+        let mut amounts = vec![
+            ("available", left.available, right.available),
+            ("held", left.held, right.held),
+            ("total", left.total, right.total),
+        ];
+        if let (Some(l), Some(r)) = (left.fees, right.fees) {
+            amounts.push(("fees", l, r));
+        }
+        for (field, l, r) in amounts {
+            // decimals compare by value, `2.0` and `2` are the same amount
+            if (r - l).abs() > tolerance {
+                differences.push(Difference {
+                    client,
+                    field,
+                    left: l.to_string(),
+                    right: r.to_string(),
+                    delta: Some(r - l),
+                });
+            }
+        }
+        if left.locked != right.locked {
+            differences.push(Difference {
+                client,
+                field: "locked",
+                left: left.locked.to_string(),
+                right: right.locked.to_string(),
+                delta: None,
+            });
+        }
+        if let (Some(l), Some(r)) = (&left.state, &right.state) {
+            if l != r {
+                differences.push(Difference {
+                    client,
+                    field: "state",
+                    left: l.clone(),
+                    right: r.clone(),
+                    delta: None,
+                });
+            }
+        }
+    }
+    for difference in &differences {
+        writer.serialize(difference)?;
+    }
+    Ok(differences.len())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -119,8 +830,23 @@ mod test {
         file.write_all(csv.as_bytes()).unwrap();
 
         Cmd {
-            path: file.path().to_path_buf(),
+            path: Some(file.path().to_path_buf()),
             output_file: Some(out.path().to_owned()),
+            fees: None,
+            store: None,
+            in_memory: false,
+            hot_tier: None,
+            metrics_addr: None,
+            metrics_file: None,
+            fraud_rules: None,
+            workers: None,
+            dispute_window: None,
+            representment_window: None,
+            reject_negative_balance: false,
+            allow_redispute: false,
+            check_invariants: false,
+            ledger: None,
+            checkpoint: None,
         }
         .exec()
         .unwrap();
@@ -134,12 +860,222 @@ mod test {
         found[1..3].sort();
         assert_eq!(
             found[0..3],
-            r#"client,available,held,total,locked
-            1,1.5,0,1.5,false
-            2,2.0,0,2.0,false"#
+            r#"client,available,held,total,locked,fees,state
+            1,1.5,0,1.5,false,0,active
+            2,2.0,0,2.0,false,0,active"#
                 .replace(" ", "")
                 .split('\n')
                 .collect::<Vec<_>>()
         );
     }
+
+    #[test]
+    fn test_check_invariants() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\n\
+             deposit,1,1,5.0\n\
+             deposit,1,2,2.0\n\
+             withdrawal,1,3,1.5\n\
+             dispute,1,1,\n\
+             dispute,1,2,1.0\n\
+             resolve,1,2,\n\
+             chargeback,1,1,\n",
+        )
+        .unwrap();
+        Cmd {
+            path: Some(path),
+            output_file: Some(dir.path().join("out.csv")),
+            fees: None,
+            store: None,
+            in_memory: false,
+            hot_tier: None,
+            metrics_addr: None,
+            metrics_file: None,
+            fraud_rules: None,
+            workers: None,
+            dispute_window: None,
+            representment_window: None,
+            reject_negative_balance: false,
+            allow_redispute: false,
+            check_invariants: true,
+            ledger: None,
+            checkpoint: None,
+        }
+        .exec()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_ledger_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, ledger) = (dir.path().join("input.csv"), dir.path().join("ledger.csv"));
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\n\
+             deposit,1,1,1.0\n\
+             deposit,2,2,2.0\n\
+             deposit,1,3,2.0\n\
+             withdrawal,1,4,1.5\n\
+             withdrawal,2,5,3.0\n",
+        )
+        .unwrap();
+        Cmd {
+            path: Some(path),
+            output_file: Some(dir.path().join("out.csv")),
+            fees: None,
+            store: None,
+            in_memory: false,
+            hot_tier: None,
+            metrics_addr: None,
+            metrics_file: None,
+            fraud_rules: None,
+            workers: None,
+            dispute_window: None,
+            representment_window: None,
+            reject_negative_balance: false,
+            allow_redispute: false,
+            check_invariants: false,
+            ledger: Some(ledger.clone()),
+            checkpoint: None,
+        }
+        .exec()
+        .unwrap();
+        // in input order, the failed withdrawal is left out
+        assert_eq!(
+            std::fs::read_to_string(ledger).unwrap(),
+            "seq,tx,debit,credit,amount
+0,1,settlement,client:1:available,1.0
+1,2,settlement,client:2:available,2.0
+2,3,settlement,client:1:available,2.0
+3,4,client:1:available,settlement,1.5
+"
+        );
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let head = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\n";
+        let tail = "dispute,1,1,\nwithdrawal,2,3,0.5\n";
+        let dir = tempfile::tempdir().unwrap();
+        let run = |input: String, workers: usize| {
+            let path = dir.path().join("input.csv");
+            std::fs::write(&path, input).unwrap();
+            Cmd {
+                path: Some(path),
+                output_file: Some(dir.path().join("out.csv")),
+                fees: None,
+                store: Some(dir.path().join("store")),
+                in_memory: false,
+                hot_tier: None,
+                metrics_addr: None,
+                metrics_file: None,
+                fraud_rules: None,
+                workers: Some(workers),
+                dispute_window: None,
+                representment_window: None,
+                reject_negative_balance: false,
+                allow_redispute: false,
+                check_invariants: true,
+                ledger: None,
+                checkpoint: Some(dir.path().join("checkpoint.toml")),
+            }
+            .exec()
+            .map(|_| Checkpoint::from_path(dir.path().join("checkpoint.toml")).unwrap())
+        };
+
+        assert_eq!(run(head.to_string(), 2).unwrap().last_row, 2);
+        // shards of clients depend on the amount of workers
+        assert!(matches!(
+            run(format!("{head}{tail}"), 3),
+            Err(Error::Engine(engine::Error::WorkersChanged {
+                saved: 2,
+                workers: 3
+            }))
+        ));
+        // the first rows are skipped, the dispute finds the deposit in the store
+        let checkpoint = run(format!("{head}{tail}"), 2).unwrap();
+        assert_eq!(checkpoint.last_row, 4);
+        let accounts = checkpoint.accounts();
+        assert_eq!(
+            (accounts[&1].available(), accounts[&1].held()),
+            (Value::ZERO, Value::new(10, 1))
+        );
+        assert_eq!(accounts[&2].available(), Value::new(15, 1));
+    }
+
+    #[test]
+    fn test_store_not_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.csv");
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+        let run = || {
+            Cmd {
+                path: Some(path.clone()),
+                output_file: Some(dir.path().join("out.csv")),
+                fees: None,
+                store: Some(dir.path().join("store")),
+                in_memory: false,
+                hot_tier: None,
+                metrics_addr: None,
+                metrics_file: None,
+                fraud_rules: None,
+                workers: Some(1),
+                dispute_window: None,
+                representment_window: None,
+                reject_negative_balance: false,
+                allow_redispute: false,
+                check_invariants: false,
+                ledger: None,
+                checkpoint: None,
+            }
+            .exec()
+        };
+
+        run().unwrap();
+        // the deposit of the first run would be found again
+        assert!(matches!(run(), Err(Error::StoreNotEmpty(_))));
+    }
+
+    #[test]
+    fn test_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        let (left, right) = (dir.path().join("left.csv"), dir.path().join("right.csv"));
+        std::fs::write(
+            &left,
+            "client,available,held,total,locked,fees,state\n\
+             1,2.0,0,2.0,false,0,active\n\
+             2,1.5,1,2.5,false,0,active\n\
+             3,0,0,0,true,0,frozen\n",
+        )
+        .unwrap();
+        // as other systems would write it, without fees and state
+        std::fs::write(
+            &right,
+            "client, available, held, total, locked\n\
+             1, 2, 0.00, 2, false\n\
+             2, 1.25, 1, 2.25, true\n\
+             4, 0, 0, 0, false\n",
+        )
+        .unwrap();
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        assert_eq!(diff(&left, &right, Value::ZERO, &mut writer).unwrap(), 5);
+        let found = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            found,
+            "client,field,left,right,delta\n\
+             2,available,1.5,1.25,-0.25\n\
+             2,total,2.5,2.25,-0.25\n\
+             2,locked,false,true,\n\
+             3,client,present,missing,\n\
+             4,client,missing,present,\n"
+        );
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        let tolerance = Value::new(25, 2);
+        assert_eq!(diff(&left, &right, tolerance, &mut writer).unwrap(), 3);
+    }
 }
@@ -0,0 +1,472 @@
+use super::common::*;
+use super::store::{self, Stats, TxRecord, TxStore};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How long a scrape may take to send its request or read the answer
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Upper bounds of histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [
+    0.000_001, 0.000_005, 0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 1.0,
+];
+
+/// Distribution of durations, in fixed buckets
+#[derive(Debug, Default)]
+pub struct Histogram {
+    // not cumulative, unlike the rendered ones
+    buckets: [AtomicU64; BUCKETS.len()],
+    sum_nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count();
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+        let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+/// Store operations which are timed
+#[derive(Debug, Clone, Copy)]
+pub enum StoreOp {
+    Insert,
+    Get,
+    Remove,
+    Scan,
+    InsertFee,
+    GetFee,
+    CommitBatch,
+    Flush,
+}
+
+impl StoreOp {
+    const ALL: [StoreOp; 8] = [
+        Self::Insert,
+        Self::Get,
+        Self::Remove,
+        Self::Scan,
+        Self::InsertFee,
+        Self::GetFee,
+        Self::CommitBatch,
+        Self::Flush,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Insert => "insert",
+            Self::Get => "get",
+            Self::Remove => "remove",
+            Self::Scan => "scan",
+            Self::InsertFee => "insert_fee",
+            Self::GetFee => "get_fee",
+            Self::CommitBatch => "commit_batch",
+            Self::Flush => "flush",
+        }
+    }
+}
+
+/// Metrics of a single worker.
+///
+/// Only the worker updates them, except for the queue depth which the engine increases
+/// when sending transactions.
+#[derive(Debug, Default)]
+pub struct WorkerMetrics {
+    processed: AtomicU64,
+    rejected: Mutex<BTreeMap<&'static str, u64>>,
+    flagged: Mutex<BTreeMap<&'static str, u64>>,
+    failed_batches: AtomicU64,
+    queued: AtomicUsize,
+    latency: Histogram,
+    store: [Histogram; StoreOp::ALL.len()],
+}
+
+impl WorkerMetrics {
+    /// Count a transaction, rejected for `reason` if any
+    pub fn transaction(&self, rejected: Option<&'static str>, elapsed: Duration) {
+        match rejected {
+            None => {
+                self.processed.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(reason) => {
+                let mut rejected = self.rejected.lock().unwrap_or_else(|e| e.into_inner());
+                *rejected.entry(reason).or_default() += 1;
+            }
+        }
+        self.latency.observe(elapsed);
+    }
+
+    /// Count a transaction flagged by a fraud rule
+    pub fn flag(&self, rule: &'static str) {
+        let mut flagged = self.flagged.lock().unwrap_or_else(|e| e.into_inner());
+        *flagged.entry(rule).or_default() += 1;
+    }
+
+    /// Count a batch whose writes could not be committed
+    pub fn failed_batch(&self) {
+        self.failed_batches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn store_op(&self, op: StoreOp, elapsed: Duration) {
+        self.store[op as usize].observe(elapsed);
+    }
+
+    pub fn enqueued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dequeued(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn processed(&self) -> u64 {
+        self.processed.load(Ordering::Relaxed)
+    }
+
+    /// Rejected transactions by reason
+    pub fn rejected(&self) -> BTreeMap<&'static str, u64> {
+        self.rejected
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Flagged transactions by fraud rule
+    pub fn flagged(&self) -> BTreeMap<&'static str, u64> {
+        self.flagged
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Metrics of all the workers of an engine
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    workers: Vec<Arc<WorkerMetrics>>,
+    queue_capacity: usize,
+}
+
+impl Metrics {
+    pub fn new(workers: Vec<Arc<WorkerMetrics>>, queue_capacity: usize) -> Self {
+        Self {
+            workers,
+            queue_capacity,
+        }
+    }
+
+    pub fn worker(&self, id: usize) -> &WorkerMetrics {
+        &self.workers[id]
+    }
+
+    /// Render in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let workers = self.workers.iter().enumerate();
+
+        header(
+            &mut out,
+            "bcc_transactions_processed_total",
+            "counter",
+            "Transactions applied",
+        );
+        for (id, worker) in workers.clone() {
+            let processed = worker.processed();
+            let _ = writeln!(
+                out,
+                "bcc_transactions_processed_total{{worker=\"{id}\"}} {processed}"
+            );
+        }
+
+        header(
+            &mut out,
+            "bcc_transactions_rejected_total",
+            "counter",
+            "Transactions rejected, by reason",
+        );
+        for (id, worker) in workers.clone() {
+            for (reason, count) in worker.rejected() {
+                let _ = writeln!(
+                    out,
+                    "bcc_transactions_rejected_total{{worker=\"{id}\",reason=\"{reason}\"}} {count}"
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "bcc_transactions_flagged_total",
+            "counter",
+            "Transactions flagged by fraud rules, by rule",
+        );
+        for (id, worker) in workers.clone() {
+            for (rule, count) in worker.flagged() {
+                let _ = writeln!(
+                    out,
+                    "bcc_transactions_flagged_total{{worker=\"{id}\",rule=\"{rule}\"}} {count}"
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "bcc_failed_batches_total",
+            "counter",
+            "Batches of transactions whose writes could not be committed, and were discarded",
+        );
+        for (id, worker) in workers.clone() {
+            let failed = worker.failed_batches.load(Ordering::Relaxed);
+            let _ = writeln!(out, "bcc_failed_batches_total{{worker=\"{id}\"}} {failed}");
+        }
+
+        header(
+            &mut out,
+            "bcc_queue_depth",
+            "gauge",
+            "Transactions waiting to be processed",
+        );
+        for (id, worker) in workers.clone() {
+            let queued = worker.queued.load(Ordering::Relaxed);
+            let _ = writeln!(out, "bcc_queue_depth{{worker=\"{id}\"}} {queued}");
+        }
+        header(
+            &mut out,
+            "bcc_queue_capacity",
+            "gauge",
+            "Transactions a worker queue can hold",
+        );
+        let _ = writeln!(out, "bcc_queue_capacity {}", self.queue_capacity);
+
+        header(
+            &mut out,
+            "bcc_transaction_duration_seconds",
+            "histogram",
+            "Time taken to process a transaction",
+        );
+        for (id, worker) in workers.clone() {
+            worker.latency.render(
+                &mut out,
+                "bcc_transaction_duration_seconds",
+                &format!("worker=\"{id}\""),
+            );
+        }
+
+        header(
+            &mut out,
+            "bcc_store_operation_duration_seconds",
+            "histogram",
+            "Time taken by transaction store operations",
+        );
+        for (id, worker) in workers {
+            for op in StoreOp::ALL {
+                let labels = format!("worker=\"{id}\",op=\"{}\"", op.name());
+                worker.store[op as usize].render(
+                    &mut out,
+                    "bcc_store_operation_duration_seconds",
+                    &labels,
+                );
+            }
+        }
+        out
+    }
+
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, self.render())
+    }
+
+    /// Serve metrics over HTTP from a background thread, for as long as the process runs.
+    ///
+    /// Any request gets the metrics back, returns the address actually bound.
+    pub fn serve<A: ToSocketAddrs>(&self, addr: A) -> std::io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let metrics = self.clone();
+        std::thread::spawn(move || {
+            // a broken or slow scrape should not hold up the next ones
+            for stream in listener.incoming().flatten() {
+                let metrics = metrics.clone();
+                std::thread::spawn(move || metrics.answer(stream));
+            }
+        });
+        Ok(addr)
+    }
+
+    fn answer(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+        stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+        // the request itself is not needed, just wait for it before answering
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request)?;
+        let body = self.render();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: text/plain; version=0.0.4\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Time operations of a store
+#[derive(Debug)]
+pub struct TimedStore {
+    inner: Box<dyn TxStore>,
+    metrics: Arc<WorkerMetrics>,
+}
+
+impl TimedStore {
+    pub fn new(inner: Box<dyn TxStore>, metrics: Arc<WorkerMetrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+fn time<T>(metrics: &WorkerMetrics, op: StoreOp, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    metrics.store_op(op, start.elapsed());
+    result
+}
+
+impl TxStore for TimedStore {
+    fn insert(
+        &mut self,
+        client: Client,
+        tx_id: TxId,
+        record: TxRecord,
+    ) -> Result<(), store::Error> {
+        time(&self.metrics, StoreOp::Insert, || {
+            self.inner.insert(client, tx_id, record)
+        })
+    }
+
+    fn get(&self, client: Client, tx_id: TxId) -> Result<TxRecord, store::Error> {
+        time(&self.metrics, StoreOp::Get, || {
+            self.inner.get(client, tx_id)
+        })
+    }
+
+    fn remove(&mut self, client: Client, tx_id: TxId) -> Result<(), store::Error> {
+        time(&self.metrics, StoreOp::Remove, || {
+            self.inner.remove(client, tx_id)
+        })
+    }
+
+    fn scan(
+        &self,
+        clients: RangeInclusive<Client>,
+    ) -> Result<Vec<(Client, TxId, TxRecord)>, store::Error> {
+        time(&self.metrics, StoreOp::Scan, || self.inner.scan(clients))
+    }
+
+    fn insert_fee(&mut self, client: Client, tx_id: TxId, fee: Value) -> Result<(), store::Error> {
+        time(&self.metrics, StoreOp::InsertFee, || {
+            self.inner.insert_fee(client, tx_id, fee)
+        })
+    }
+
+    fn get_fee(&self, client: Client, tx_id: TxId) -> Result<Value, store::Error> {
+        time(&self.metrics, StoreOp::GetFee, || {
+            self.inner.get_fee(client, tx_id)
+        })
+    }
+
+    fn stats(&self) -> Result<Stats, store::Error> {
+        self.inner.stats()
+    }
+
+    fn compact(&mut self) -> Result<bool, store::Error> {
+        self.inner.compact()
+    }
+
+    fn flush(&mut self) -> Result<(), store::Error> {
+        time(&self.metrics, StoreOp::Flush, || self.inner.flush())
+    }
+
+    fn begin_batch(&mut self) -> Result<(), store::Error> {
+        self.inner.begin_batch()
+    }
+
+    fn commit_batch(&mut self) -> Result<(), store::Error> {
+        time(&self.metrics, StoreOp::CommitBatch, || {
+            self.inner.commit_batch()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(3));
+        histogram.observe(Duration::from_millis(2));
+        histogram.observe(Duration::from_secs(2));
+        let mut out = String::new();
+        histogram.render(&mut out, "h", "w=\"0\"");
+        assert!(out.contains("h_bucket{w=\"0\",le=\"0.000001\"} 0\n"));
+        assert!(out.contains("h_bucket{w=\"0\",le=\"0.000005\"} 1\n"));
+        assert!(out.contains("h_bucket{w=\"0\",le=\"1\"} 2\n"));
+        assert!(out.contains("h_bucket{w=\"0\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("h_count{w=\"0\"} 3\n"));
+    }
+
+    #[test]
+    fn test_serve() {
+        let worker = Arc::new(WorkerMetrics::default());
+        worker.transaction(None, Duration::from_micros(1));
+        worker.transaction(Some("account_frozen"), Duration::from_micros(1));
+        let metrics = Metrics::new(vec![worker], 100);
+        let addr = metrics.serve("127.0.0.1:0").unwrap();
+
+        // a client connecting without sending anything does not block others
+        let _idle = TcpStream::connect(addr).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("bcc_transactions_processed_total{worker=\"0\"} 1\n"));
+        assert!(response.contains(
+            "bcc_transactions_rejected_total{worker=\"0\",reason=\"account_frozen\"} 1\n"
+        ));
+        assert!(response.contains("bcc_queue_capacity 100\n"));
+    }
+}
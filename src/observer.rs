@@ -0,0 +1,67 @@
+use super::{account::Account, common::*, engine::Error, transaction::Transaction};
+use std::sync::Arc;
+
+/// Hooks into engine activity, e.g. for alerting or analytics.
+///
+/// Callbacks are invoked from worker threads, for each client in the order of the input.
+/// Transactions are only reported as applied once their batch is committed to the store,
+/// those of a batch which could not be committed are reported as rejected instead, and
+/// their worker stops.
+pub trait EngineObserver: Send + Sync {
+    /// `tx` changed the account of its client from `before` (`None` if new) to `after`
+    fn on_applied(&self, _tx: &Transaction, _before: Option<&Account>, _after: &Account) {}
+
+    fn on_rejected(&self, _tx: &Transaction, _error: &Error) {}
+
+    /// The account of `client` was just frozen, always reported after the transaction causing it
+    fn on_frozen(&self, _client: Client) {}
+
+    /// `tx` matched a fraud rule whose action is to flag it, reported before its outcome
+    fn on_flagged(&self, _tx: &Transaction, _rule: &str) {}
+}
+
+/// Observers registered with an engine, each called in registration order
+#[derive(Clone, Default)]
+pub struct Observers(Vec<Arc<dyn EngineObserver>>);
+
+impl std::fmt::Debug for Observers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+impl Observers {
+    pub fn push(&mut self, observer: Arc<dyn EngineObserver>) {
+        self.0.push(observer);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl EngineObserver for Observers {
+    fn on_applied(&self, tx: &Transaction, before: Option<&Account>, after: &Account) {
+        for observer in &self.0 {
+            observer.on_applied(tx, before, after);
+        }
+    }
+
+    fn on_rejected(&self, tx: &Transaction, error: &Error) {
+        for observer in &self.0 {
+            observer.on_rejected(tx, error);
+        }
+    }
+
+    fn on_frozen(&self, client: Client) {
+        for observer in &self.0 {
+            observer.on_frozen(client);
+        }
+    }
+
+    fn on_flagged(&self, tx: &Transaction, rule: &str) {
+        for observer in &self.0 {
+            observer.on_flagged(tx, rule);
+        }
+    }
+}
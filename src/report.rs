@@ -0,0 +1,175 @@
+use super::{account::Account, common::*, transaction::Transaction};
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Activity of a client, for reporting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct ClientStats {
+    pub deposited: Value,
+    pub withdrawn: Value,
+    pub disputed: Value,
+    pub resolved: Value,
+    pub charged_back: Value,
+    pub represented: Value,
+    pub fees: Value,
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+    pub representments: u64,
+    pub rejected: u64,
+}
+
+pub type Aggregates = HashMap<Client, ClientStats>;
+
+impl ClientStats {
+    /// Account for `tx`, taking the account of its client from `before` to `after` if applied.
+    ///
+    /// Amounts are worked out from the account change, so that transactions referring to
+    /// others (e.g. disputes) need not look them up.
+    pub fn record(
+        &mut self,
+        tx: &Transaction,
+        before: Option<&Account>,
+        after: Option<&Account>,
+        applied: bool,
+    ) {
+        if !applied {
+            self.rejected += 1;
+            return;
+        }
+        let available = |account: Option<&Account>| account.map_or(Value::ZERO, Account::available);
+        let held = |account: Option<&Account>| account.map_or(Value::ZERO, Account::held);
+        let fees = |account: Option<&Account>| account.map_or(Value::ZERO, Account::fees);
+        let held_change = held(after) - held(before);
+        self.fees += fees(after) - fees(before);
+        match tx {
+            Transaction::Deposit { value, .. } => {
+                self.deposited += value;
+                self.deposits += 1;
+            }
+            Transaction::Withdrawal { value, .. } => {
+                self.withdrawn += value;
+                self.withdrawals += 1;
+            }
+            Transaction::Dispute { .. } => {
+                self.disputed += held_change;
+                self.disputes += 1;
+            }
+            Transaction::Resolve { .. } => {
+                self.resolved -= held_change;
+                self.resolves += 1;
+            }
+            Transaction::Chargeback { .. } => {
+                self.charged_back -= held_change;
+                self.chargebacks += 1;
+            }
+            Transaction::Representment { .. } => {
+                self.represented += available(after) - available(before);
+                self.representments += 1;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &ClientStats) {
+        self.deposited += other.deposited;
+        self.withdrawn += other.withdrawn;
+        self.disputed += other.disputed;
+        self.resolved += other.resolved;
+        self.charged_back += other.charged_back;
+        self.represented += other.represented;
+        self.fees += other.fees;
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.disputes += other.disputes;
+        self.resolves += other.resolves;
+        self.chargebacks += other.chargebacks;
+        self.representments += other.representments;
+        self.rejected += other.rejected;
+    }
+
+    /// Funds the activity leaves the client with, held ones included.
+    /// This is the `total` of its account if it started from scratch.
+    pub fn balance(&self) -> Value {
+        self.deposited - self.withdrawn - self.charged_back + self.represented - self.fees
+    }
+}
+
+/// Totals of all the clients
+pub fn totals<'a, I: IntoIterator<Item = &'a ClientStats>>(stats: I) -> ClientStats {
+    stats
+        .into_iter()
+        .fold(ClientStats::default(), |mut totals, client| {
+            totals.merge(client);
+            totals
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut stats = ClientStats::default();
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx_id: 1,
+            value: Value::TEN,
+        };
+        let dispute = Transaction::Dispute {
+            client: 1,
+            tx_id: 1,
+            value: None,
+        };
+        let chargeback = Transaction::Chargeback {
+            client: 1,
+            tx_id: 1,
+            value: None,
+        };
+        let deposited = Account::default().deposit(Value::TEN).unwrap();
+        let disputed = deposited.freeze_funds(Value::TEN).unwrap();
+        let charged_back = disputed.chargeback(Value::TEN, Value::ONE).unwrap();
+        stats.record(&deposit, None, Some(&deposited), true);
+        stats.record(&dispute, Some(&deposited), Some(&disputed), true);
+        stats.record(&chargeback, Some(&disputed), Some(&charged_back), true);
+        stats.record(&deposit, Some(&charged_back), Some(&charged_back), false);
+
+        assert_eq!(
+            (
+                stats.deposited,
+                stats.disputed,
+                stats.charged_back,
+                stats.fees
+            ),
+            (Value::TEN, Value::TEN, Value::TEN, Value::ONE)
+        );
+        assert_eq!(
+            (stats.deposits, stats.chargebacks, stats.rejected),
+            (1, 1, 1)
+        );
+        assert_eq!(
+            stats.balance(),
+            charged_back.available() + charged_back.held()
+        );
+
+        let representment = Transaction::Representment {
+            client: 1,
+            tx_id: 1,
+        };
+        let represented = charged_back.represent(Value::TEN).unwrap();
+        stats.record(
+            &representment,
+            Some(&charged_back),
+            Some(&represented),
+            true,
+        );
+        assert_eq!((stats.represented, stats.representments), (Value::TEN, 1));
+        assert_eq!(
+            stats.balance(),
+            represented.available() + represented.held()
+        );
+        assert_eq!(totals([&stats, &stats]).deposits, 2);
+    }
+}
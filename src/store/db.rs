@@ -0,0 +1,570 @@
+use super::{compute_id, split_id, Error, Stats, TxRecord, TxStore};
+use crate::common::*;
+use redb::{
+    Database, ReadOnlyTable, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle,
+    WriteTransaction,
+};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+const TX_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("records");
+// Records written before their encoding was versioned, migrated to `TX_TABLE` on open
+const LEGACY_TX_TABLE: TableDefinition<u64, LegacyTxRecord> = TableDefinition::new("transactions");
+// Fees are kept apart from transactions, keyed by the transaction they were charged on.
+const FEE_TABLE: TableDefinition<u64, [u8; 16]> = TableDefinition::new("fees");
+// Records which could not be read, moved aside by `TransactionStore::quarantine`, or when
+// migrating legacy ones
+const QUARANTINE_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("quarantine");
+
+/// Transaction store backed by an embedded database.
+///
+/// In essence, adopt a multi-tiered system for transaction bookkeeping to avoid
+/// having everything in memory.
+/// At the moment it's just an embedded database because it was easy to add,
+/// but a custom ds may be better.
+/// It's probably useful to look at performance in more detail for production systems.
+///
+/// If the possibility to dispute transactions expire after some time, remove such
+/// transactions from the system.
+pub struct TransactionStore {
+    // write transaction shared by all the writes in a batch, fields are dropped in order and
+    // an open batch has to go before the database
+    batch: Option<WriteTransaction>,
+    db: Database,
+    // handle on the db file, only used to inspect its size
+    file: File,
+}
+
+/// Outcome of [`TransactionStore::check`]
+#[derive(Debug, Default)]
+pub struct Check {
+    /// Records which could be read
+    pub records: u64,
+    /// Funds held for each client, according to its records
+    pub held: BTreeMap<Client, Value>,
+    /// Records which could not be read, and why
+    pub corrupted: Vec<(Client, TxId, Error)>,
+}
+
+impl std::fmt::Debug for TransactionStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransactionStore")
+            .field("db", &self.db)
+            .field("batch", &self.batch.is_some())
+            .finish()
+    }
+}
+
+// Raw bytes of the table `TX_TABLE` replaced, decoded with `TxRecord::decode_legacy`.
+// The type name must stay the same for redb to open the table.
+#[derive(Debug)]
+struct LegacyTxRecord;
+
+impl redb::Value for LegacyTxRecord {
+    type SelfType<'a> = &'a [u8];
+    type AsBytes<'a> = &'a [u8];
+
+    fn fixed_width() -> Option<usize> {
+        Some(17)
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> Self::SelfType<'a>
+    where
+        Self: 'a,
+    {
+        data
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        value
+    }
+
+    fn type_name() -> redb::TypeName {
+        redb::TypeName::new("TxRecord")
+    }
+}
+
+impl Default for TransactionStore {
+    fn default() -> Self {
+        Self::new().expect("Failed to create default TransactionStore")
+    }
+}
+
+impl TransactionStore {
+    pub fn new() -> Result<Self, Error> {
+        Self::from_file(tempfile::tempfile().map_err(Error::TempFile)?)
+    }
+
+    /// Open the store persisted at `path`, creating it if it does not exist.
+    ///
+    /// Writes are not flushed to disk as they happen, call [`TxStore::flush`] once done.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(Error::Io)?;
+        Self::from_file(file)
+    }
+
+    fn from_file(file: File) -> Result<Self, Error> {
+        let store = Self {
+            file: file.try_clone().map_err(Error::Io)?,
+            db: Database::builder().create_file(file)?,
+            batch: None,
+        };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    // Re-encode records written in the legacy format, if any
+    fn migrate(&self) -> Result<(), Error> {
+        let write_txn = self.db.begin_write()?;
+        let legacy = write_txn
+            .list_tables()?
+            .any(|table| table.name() == LEGACY_TX_TABLE.name());
+        if !legacy {
+            return Ok(());
+        }
+        {
+            let old = write_txn.open_table(LEGACY_TX_TABLE)?;
+            let mut new = write_txn.open_table(TX_TABLE)?;
+            let mut quarantine = write_txn.open_table(QUARANTINE_TABLE)?;
+            tracing::info!(
+                records = old.len()?,
+                "migrating records to the versioned encoding"
+            );
+            for entry in old.iter()? {
+                let (id, bytes) = entry?;
+                // failing would leave the store impossible to open, let alone to repair
+                match TxRecord::decode_legacy(bytes.value()) {
+                    Ok(record) => new.insert(id.value(), record.encode().as_slice())?,
+                    Err(e) => {
+                        let (client, tx_id) = split_id(id.value());
+                        tracing::warn!(client, tx_id, error = %e, "quarantined legacy record");
+                        quarantine.insert(id.value(), bytes.value())?
+                    }
+                };
+            }
+        }
+        write_txn.delete_table(LEGACY_TX_TABLE)?;
+        // a failure leaves the legacy table untouched, to be migrated next time
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    /// Open the store for a shard in `dir`, each worker has its own
+    pub fn open_shard<P: AsRef<Path>>(dir: P, shard: usize) -> Result<Self, Error> {
+        std::fs::create_dir_all(&dir).map_err(Error::Io)?;
+        Self::open(dir.as_ref().join(format!("shard-{shard}.redb")))
+    }
+
+    /// Open all shards persisted in `dir`, along with their ids, by id
+    pub fn open_shards<P: AsRef<Path>>(dir: P) -> Result<Vec<(usize, Self)>, Error> {
+        let paths = std::fs::read_dir(dir)
+            .map_err(Error::Io)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<PathBuf>, _>>()
+            .map_err(Error::Io)?;
+        // as named by `open_shard`, other files are left alone
+        let mut shards = paths
+            .into_iter()
+            .filter_map(|path| {
+                let shard = path
+                    .file_name()?
+                    .to_str()?
+                    .strip_prefix("shard-")?
+                    .strip_suffix(".redb")?
+                    .parse()
+                    .ok()?;
+                Some((shard, path))
+            })
+            .collect::<Vec<_>>();
+        shards.sort();
+        shards
+            .into_iter()
+            .map(|(shard, path)| Ok((shard, Self::open(path)?)))
+            .collect()
+    }
+
+    /// Decode every record, reporting those which cannot be instead of failing.
+    pub fn check(&self) -> Result<Check, Error> {
+        let mut check = Check::default();
+        let Some(table) = self.read_table(TX_TABLE)? else {
+            return Ok(check);
+        };
+        for entry in table.iter()? {
+            let (id, bytes) = entry?;
+            let (client, tx_id) = split_id(id.value());
+            match TxRecord::decode(bytes.value()) {
+                Ok(record) => {
+                    check.records += 1;
+                    *check.held.entry(client).or_default() += record.held;
+                }
+                Err(e) => check.corrupted.push((client, tx_id, e)),
+            }
+        }
+        Ok(check)
+    }
+
+    /// Move records out of the way, as they are, to a table of their own.
+    ///
+    /// Meant for records which cannot be read, so that the rest of the store can be.
+    #[tracing::instrument(level = "info", skip(self))]
+    pub fn quarantine(&mut self, records: &[(Client, TxId)]) -> Result<(), Error> {
+        self.commit_batch()?;
+        // durable, this is a one off maintenance operation
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut txs = write_txn.open_table(TX_TABLE)?;
+            let mut quarantine = write_txn.open_table(QUARANTINE_TABLE)?;
+            for &(client, tx_id) in records {
+                let id = compute_id(client, tx_id);
+                if let Some(bytes) = txs.remove(id)? {
+                    quarantine.insert(id, bytes.value())?;
+                }
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    // Open a table for reading, `None` if nothing was ever written to it
+    fn read_table<V: redb::Value + 'static>(
+        &self,
+        table: TableDefinition<u64, V>,
+    ) -> Result<Option<ReadOnlyTable<u64, V>>, Error> {
+        match self.db.begin_read()?.open_table(table) {
+            Err(redb::TableError::TableDoesNotExist(_)) => Ok(None),
+            table => Ok(Some(table?)),
+        }
+    }
+
+    // Run `f` in the open batch, or in a transaction of its own if there is none
+    fn write<F>(&self, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&WriteTransaction) -> Result<(), Error>,
+    {
+        if let Some(batch) = &self.batch {
+            return f(batch);
+        }
+        let mut write_txn = self.db.begin_write()?;
+        // Avoid flushing to disk, this is just a temporary store.
+        write_txn.set_durability(redb::Durability::None);
+        f(&write_txn)?;
+        write_txn.commit()?;
+        Ok(())
+    }
+
+    fn lookup<T: ReadableTable<u64, &'static [u8]>>(table: &T, id: u64) -> Result<TxRecord, Error> {
+        TxRecord::decode(table.get(id)?.ok_or(Error::NotFound)?.value())
+    }
+
+    fn lookup_fee<T: ReadableTable<u64, [u8; 16]>>(table: &T, id: u64) -> Result<Value, Error> {
+        Ok(Value::deserialize(
+            table.get(id)?.ok_or(Error::NotFound)?.value(),
+        ))
+    }
+
+    fn range<T: ReadableTable<u64, &'static [u8]>>(
+        table: &T,
+        clients: RangeInclusive<Client>,
+    ) -> Result<Vec<(Client, TxId, TxRecord)>, Error> {
+        table
+            .range(compute_id(*clients.start(), TxId::MIN)..=compute_id(*clients.end(), TxId::MAX))?
+            .map(|entry| {
+                let (id, record) = entry?;
+                let (client, tx_id) = split_id(id.value());
+                Ok((client, tx_id, TxRecord::decode(record.value())?))
+            })
+            .collect()
+    }
+}
+
+// Reads go through the open batch, if any, to see its writes.
+impl TxStore for TransactionStore {
+    /// Insert a new transaction in the database.
+    #[tracing::instrument(level = "trace", skip(self, record))]
+    fn insert(&mut self, client: Client, tx_id: TxId, record: TxRecord) -> Result<(), Error> {
+        let id = compute_id(client, tx_id);
+        self.write(|txn| {
+            txn.open_table(TX_TABLE)?
+                .insert(id, record.encode().as_slice())?;
+            Ok(())
+        })
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn remove(&mut self, client: Client, tx_id: TxId) -> Result<(), Error> {
+        let id = compute_id(client, tx_id);
+        self.write(|txn| {
+            txn.open_table(TX_TABLE)?.remove(id)?;
+            Ok(())
+        })
+    }
+
+    /// Fetch a transaction
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn get(&self, client: Client, tx_id: TxId) -> Result<TxRecord, Error> {
+        let id = compute_id(client, tx_id);
+        match &self.batch {
+            Some(batch) => Self::lookup(&batch.open_table(TX_TABLE)?, id),
+            None => Self::lookup(&self.read_table(TX_TABLE)?.ok_or(Error::NotFound)?, id),
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn scan(
+        &self,
+        clients: RangeInclusive<Client>,
+    ) -> Result<Vec<(Client, TxId, TxRecord)>, Error> {
+        match &self.batch {
+            Some(batch) => Self::range(&batch.open_table(TX_TABLE)?, clients),
+            None => match self.read_table(TX_TABLE)? {
+                Some(table) => Self::range(&table, clients),
+                None => Ok(Vec::new()),
+            },
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn insert_fee(&mut self, client: Client, tx_id: TxId, fee: Value) -> Result<(), Error> {
+        let id = compute_id(client, tx_id);
+        self.write(|txn| {
+            txn.open_table(FEE_TABLE)?.insert(id, fee.serialize())?;
+            Ok(())
+        })
+    }
+
+    #[tracing::instrument(level = "trace", skip(self))]
+    fn get_fee(&self, client: Client, tx_id: TxId) -> Result<Value, Error> {
+        let id = compute_id(client, tx_id);
+        match &self.batch {
+            Some(batch) => Self::lookup_fee(&batch.open_table(FEE_TABLE)?, id),
+            None => Self::lookup_fee(&self.read_table(FEE_TABLE)?.ok_or(Error::NotFound)?, id),
+        }
+    }
+
+    /// Writes in a batch which has not been committed yet are not counted.
+    fn stats(&self) -> Result<Stats, Error> {
+        let mut stats = Stats {
+            bytes_on_disk: self.file.metadata().map_err(Error::Io)?.len(),
+            ..Stats::default()
+        };
+        if let Some(table) = self.read_table(TX_TABLE)? {
+            for entry in table.iter()? {
+                let (id, record) = entry?;
+                stats.count(split_id(id.value()).0, &TxRecord::decode(record.value())?);
+            }
+        }
+        if let Some(table) = self.read_table(FEE_TABLE)? {
+            stats.fees = table.len()?;
+        }
+        Ok(stats)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn compact(&mut self) -> Result<bool, Error> {
+        // Space is only freed by durable commits
+        self.flush()?;
+        Ok(self.db.compact()?)
+    }
+
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn flush(&mut self) -> Result<(), Error> {
+        self.commit_batch()?;
+        // Commit an empty transaction with the default durability, this persists
+        // the non durable ones committed before it.
+        self.db.begin_write()?.commit()?;
+        Ok(())
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        if self.batch.is_none() {
+            let mut write_txn = self.db.begin_write()?;
+            // Avoid flushing to disk, this is just a temporary store.
+            write_txn.set_durability(redb::Durability::None);
+            self.batch = Some(write_txn);
+        }
+        Ok(())
+    }
+
+    fn commit_batch(&mut self) -> Result<(), Error> {
+        // a failed commit rolls back the whole write transaction
+        if let Some(batch) = self.batch.take() {
+            batch.commit()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{disputed, record};
+    use super::*;
+    use crate::transaction::TxStatus;
+
+    #[test]
+    fn test_stats_compact() {
+        let mut store = TransactionStore::new().unwrap();
+        for tx_id in 0..100 {
+            store.insert(tx_id as Client % 2, tx_id, record(1)).unwrap();
+        }
+        store
+            .insert(1, 100, record(1).with_status(TxStatus::Disputed, 1))
+            .unwrap();
+        store.insert_fee(1, 100, Value::ONE).unwrap();
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.undisputed, 100);
+        assert_eq!(stats.disputed, 1);
+        assert_eq!(stats.fees, 1);
+        assert_eq!(
+            stats.clients,
+            std::collections::BTreeMap::from([(0, 50), (1, 51)])
+        );
+
+        for tx_id in 0..100 {
+            store.remove(tx_id as Client % 2, tx_id).unwrap();
+        }
+        store.compact().unwrap();
+        let compacted = store.stats().unwrap();
+        assert_eq!(compacted.undisputed, 0);
+        assert!(compacted.bytes_on_disk <= stats.bytes_on_disk);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let mut store = TransactionStore::open_shard(dir.path(), 0).unwrap();
+            store.insert(1, 1, record(1)).unwrap();
+            store.flush().unwrap();
+        }
+        let mut shards = TransactionStore::open_shards(dir.path()).unwrap();
+        assert_eq!(shards.len(), 1);
+        assert_eq!(shards[0].1.get(1, 1).unwrap().value, Value::ONE);
+
+        // batched writes are only visible outside the batch once committed
+        let store = &mut shards[0].1;
+        store.begin_batch().unwrap();
+        store.insert(1, 2, record(2)).unwrap();
+        assert_eq!(store.get(1, 2).unwrap().value, Value::TWO);
+        assert_eq!(store.scan(1..=1).unwrap().len(), 2);
+        assert_eq!(store.stats().unwrap().undisputed, 1);
+        store.commit_batch().unwrap();
+        assert_eq!(store.stats().unwrap().undisputed, 2);
+    }
+
+    #[test]
+    fn test_open_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        for shard in [10, 2] {
+            TransactionStore::open_shard(dir.path(), shard).unwrap();
+        }
+        std::fs::write(dir.path().join("shard-x.redb"), "").unwrap();
+        let shards = TransactionStore::open_shards(dir.path()).unwrap();
+        // by id rather than by name
+        assert_eq!(
+            shards.iter().map(|(shard, _)| *shard).collect::<Vec<_>>(),
+            [2, 10]
+        );
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        {
+            let db = Database::create(file.path()).unwrap();
+            let txn = db.begin_write().unwrap();
+            {
+                let mut table = txn.open_table(LEGACY_TX_TABLE).unwrap();
+                let mut bytes = [0u8; 17];
+                bytes[..16].copy_from_slice(&Value::TEN.serialize());
+                bytes[16] = 1;
+                table.insert(compute_id(1, 2), bytes.as_slice()).unwrap();
+                // unknown status
+                bytes[16] = 9;
+                table.insert(compute_id(1, 4), bytes.as_slice()).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+
+        let store = TransactionStore::open(file.path()).unwrap();
+        let record = store.get(1, 2).unwrap();
+        assert_eq!(
+            (record.value, record.status),
+            (Value::TEN, TxStatus::Disputed)
+        );
+        // set aside rather than failing to open
+        assert!(matches!(store.get(1, 4), Err(Error::NotFound)));
+        assert_eq!(
+            store
+                .read_table(QUARANTINE_TABLE)
+                .unwrap()
+                .unwrap()
+                .len()
+                .unwrap(),
+            1
+        );
+        assert!(store
+            .db
+            .begin_read()
+            .unwrap()
+            .list_tables()
+            .unwrap()
+            .all(|table| table.name() != LEGACY_TX_TABLE.name()));
+
+        // unknown encodings are reported instead of panicking
+        store
+            .write(|txn| {
+                txn.open_table(TX_TABLE)?
+                    .insert(compute_id(1, 3), [9u8].as_slice())?;
+                Ok(())
+            })
+            .unwrap();
+        assert!(matches!(store.get(1, 3), Err(Error::Version(9))));
+        assert!(store.scan(1..=1).is_err());
+    }
+
+    #[test]
+    fn test_check_quarantine() {
+        let mut store = TransactionStore::new().unwrap();
+        store.insert(1, 1, record(1)).unwrap();
+        store.insert(1, 2, disputed(2)).unwrap();
+        store.insert(2, 3, disputed(3)).unwrap();
+        store
+            .write(|txn| {
+                txn.open_table(TX_TABLE)?
+                    .insert(compute_id(2, 4), [1u8, 2].as_slice())?;
+                Ok(())
+            })
+            .unwrap();
+
+        let check = store.check().unwrap();
+        assert_eq!(check.records, 3);
+        assert_eq!(
+            check.held,
+            BTreeMap::from([(1, Value::TWO), (2, Value::new(3, 0))])
+        );
+        assert_eq!(check.corrupted.len(), 1);
+        assert_eq!(check.corrupted[0].0, 2);
+        assert_eq!(check.corrupted[0].1, 4);
+
+        store.quarantine(&[(2, 4)]).unwrap();
+        assert!(store.check().unwrap().corrupted.is_empty());
+        assert_eq!(store.scan(2..=2).unwrap().len(), 1);
+        let quarantined = store.read_table(QUARANTINE_TABLE).unwrap().unwrap();
+        assert_eq!(
+            quarantined.get(compute_id(2, 4)).unwrap().unwrap().value(),
+            [1u8, 2]
+        );
+    }
+}
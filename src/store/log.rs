@@ -0,0 +1,758 @@
+use super::{compute_id, split_id, Error, Stats, TxRecord, TxStore};
+use crate::common::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+
+// Entries are made of a tag, an id, the length of their payload and the payload itself
+const ENTRY_HEADER: usize = 11;
+// Magic bytes and the id of the first segment this one covers, see `LogStore::open_dir`
+const HEADER_SIZE: u64 = 12;
+const MAGIC: &[u8; 8] = b"bcclog01";
+
+const TX: u8 = 0;
+const FEE: u8 = 1;
+const REMOVE_TX: u8 = 2;
+
+const DEFAULT_SEGMENT_SIZE: u32 = 64 << 20;
+// Entries are buffered in memory up to this size before being written
+const WRITE_BUFFER: usize = 64 << 10;
+// Full segments to accumulate before merging them
+const MERGE_THRESHOLD: usize = 4;
+
+/// Transaction store writing records to an append-only log.
+///
+/// The engine mostly appends records and only looks them up on disputes, so writes
+/// are kept sequential and the position of the last version of each record is tracked
+/// by an in-memory index. Lookups then take a single read.
+///
+/// The log is split into segments of bounded size. Once enough of them are full they are
+/// merged in a background thread, dropping the records overwritten or removed since.
+pub struct LogStore {
+    dir: PathBuf,
+    // all segments, the last one is the one being written
+    segments: BTreeMap<u32, File>,
+    active: u32,
+    // bytes of the active segment already written to its file
+    written: u64,
+    // entries appended to the active segment but not written to its file yet
+    pending: Vec<u8>,
+    segment_size: u32,
+    txs: BTreeMap<u64, Location>,
+    fees: HashMap<u64, Location>,
+    merge: Option<JoinHandle<Result<Merged, Error>>>,
+    // `None` if no batch is in progress
+    batch: Option<Batch>,
+    // removes the directory of temporary stores once dropped
+    _temp: Option<tempfile::TempDir>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    segment: u32,
+    // merged segments are not capped by the segment size, offsets may go past 4 GiB
+    offset: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Kind {
+    Tx,
+    Fee,
+}
+
+enum Entry {
+    Tx(TxRecord),
+    Fee(Value),
+    RemoveTx,
+}
+
+// Entries of a batch are kept in `pending` until it is committed
+struct Batch {
+    // length of `pending` when the batch started
+    start: usize,
+    // index entries as they were before the batch, for those changed in it
+    undo: HashMap<(Kind, u64), Option<Location>>,
+}
+
+// Result of merging segments, to be applied to the index
+struct Merged {
+    segments: Vec<u32>,
+    // merged entries are rewritten in the segment with the highest id
+    file: File,
+    moved: Vec<(Kind, u64, Location, u64)>,
+}
+
+impl std::fmt::Debug for LogStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LogStore")
+            .field("dir", &self.dir)
+            .field("segments", &self.segments.len())
+            .field("txs", &self.txs.len())
+            .field("merging", &self.merge.is_some())
+            .finish()
+    }
+}
+
+impl LogStore {
+    /// Open a store in a temporary directory, removed once the store is dropped
+    pub fn new() -> Result<Self, Error> {
+        let temp = tempfile::tempdir().map_err(Error::TempFile)?;
+        Self::open_dir(temp.path().to_owned(), Some(temp))
+    }
+
+    /// Open the store persisted in `dir`, creating it if it does not exist.
+    ///
+    /// Writes are buffered, call [`TxStore::flush`] once done.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, Error> {
+        Self::open_dir(dir.as_ref().to_owned(), None)
+    }
+
+    /// Open the store for a shard in `dir`, each worker has its own
+    pub fn open_shard<P: AsRef<Path>>(dir: P, shard: usize) -> Result<Self, Error> {
+        Self::open(dir.as_ref().join(format!("shard-{shard}.log")))
+    }
+
+    /// Start a new segment once the current one reaches `bytes`.
+    pub fn segment_size(mut self, bytes: u32) -> Self {
+        self.segment_size = bytes;
+        self
+    }
+
+    fn open_dir(dir: PathBuf, temp: Option<tempfile::TempDir>) -> Result<Self, Error> {
+        std::fs::create_dir_all(&dir).map_err(Error::Io)?;
+        let mut found = BTreeMap::new();
+        for entry in std::fs::read_dir(&dir).map_err(Error::Io)? {
+            let path = entry.map_err(Error::Io)?.path();
+            if let Some(id) = segment_id(&path) {
+                let mut file = File::open(&path).map_err(Error::Io)?;
+                found.insert(id, read_header(&mut file)?);
+            } else if path.extension().is_some_and(|ext| ext == "merging") {
+                // interrupted merge, the segments it was merging are still there
+                std::fs::remove_file(&path).map_err(Error::Io)?;
+            }
+        }
+
+        // A merged segment replaces all the segments from the first one it covers.
+        // Those are removed once the merge is done, but may be left over by a crash.
+        let mut covered = u32::MAX;
+        let mut live = Vec::new();
+        for (&id, &first) in found.iter().rev() {
+            if id >= covered {
+                std::fs::remove_file(segment_path(&dir, id)).map_err(Error::Io)?;
+            } else {
+                live.push(id);
+                covered = first;
+            }
+        }
+
+        let mut store = Self {
+            active: live.first().map_or(0, |id| id + 1),
+            dir,
+            segments: BTreeMap::new(),
+            written: HEADER_SIZE,
+            pending: Vec::new(),
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            txs: BTreeMap::new(),
+            fees: HashMap::new(),
+            merge: None,
+            batch: None,
+            _temp: temp,
+        };
+        for id in live.into_iter().rev() {
+            let mut file = File::options()
+                .read(true)
+                .write(true)
+                .open(segment_path(&store.dir, id))
+                .map_err(Error::Io)?;
+            for (offset, bytes) in read_entries(&mut file)? {
+                let (key, entry) = decode(&bytes)?;
+                store.index(
+                    key,
+                    &entry,
+                    Location {
+                        segment: id,
+                        offset,
+                    },
+                );
+            }
+            store.segments.insert(id, file);
+        }
+        let file = create_segment(&segment_path(&store.dir, store.active), store.active)?;
+        store.segments.insert(store.active, file);
+        Ok(store)
+    }
+
+    fn index(&mut self, key: u64, entry: &Entry, location: Location) {
+        let (kind, previous) = match entry {
+            Entry::Tx(_) => (Kind::Tx, self.txs.insert(key, location)),
+            Entry::Fee(_) => (Kind::Fee, self.fees.insert(key, location)),
+            Entry::RemoveTx => (Kind::Tx, self.txs.remove(&key)),
+        };
+        if let Some(batch) = &mut self.batch {
+            batch.undo.entry((kind, key)).or_insert(previous);
+        }
+    }
+
+    // Drop the entries of the current batch and point the index back to what it was
+    fn rollback(&mut self) {
+        let Some(batch) = self.batch.take() else {
+            return;
+        };
+        self.pending.truncate(batch.start);
+        for ((kind, key), location) in batch.undo {
+            match (kind, location) {
+                (Kind::Tx, Some(location)) => {
+                    self.txs.insert(key, location);
+                }
+                (Kind::Tx, None) => {
+                    self.txs.remove(&key);
+                }
+                (Kind::Fee, Some(location)) => {
+                    self.fees.insert(key, location);
+                }
+                (Kind::Fee, None) => {
+                    self.fees.remove(&key);
+                }
+            }
+        }
+    }
+
+    // Write out buffered entries and start a new segment when needed, merging sealed
+    // ones in the meantime.
+    fn prepare(&mut self) -> Result<(), Error> {
+        self.finish_merge(false)?;
+        if self.pending.len() >= WRITE_BUFFER {
+            self.write_out()?;
+        }
+        if self.written + self.pending.len() as u64 >= self.segment_size as u64 {
+            self.roll()?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, key: u64, entry: Entry) -> Result<(), Error> {
+        // Do anything that may fail before the write, so that errors leave the store untouched.
+        // Within a batch this is done when it starts, nothing is written before the commit.
+        if self.batch.is_none() {
+            self.prepare()?;
+        }
+
+        let offset = self.written + self.pending.len() as u64;
+        let location = Location {
+            segment: self.active,
+            offset,
+        };
+        self.pending.extend_from_slice(&encode(key, &entry));
+        self.index(key, &entry, location);
+        Ok(())
+    }
+
+    fn read(&self, location: Location) -> Result<Entry, Error> {
+        let offset = location.offset;
+        let bytes = if location.segment == self.active && offset >= self.written {
+            let start = (offset - self.written) as usize;
+            let len = entry_len(&self.pending[start..])?;
+            self.pending[start..start + len].to_vec()
+        } else {
+            let mut file = &self.segments[&location.segment];
+            let mut bytes = vec![0; ENTRY_HEADER];
+            file.seek(SeekFrom::Start(offset)).map_err(Error::Io)?;
+            file.read_exact(&mut bytes).map_err(Error::Io)?;
+            bytes.resize(entry_len(&bytes)?, 0);
+            file.read_exact(&mut bytes[ENTRY_HEADER..])
+                .map_err(Error::Io)?;
+            bytes
+        };
+        Ok(decode(&bytes)?.1)
+    }
+
+    fn read_tx(&self, location: Location) -> Result<TxRecord, Error> {
+        match self.read(location)? {
+            Entry::Tx(record) => Ok(record),
+            _ => Err(Error::Corrupt("index does not point to a transaction")),
+        }
+    }
+
+    // Write buffered entries to the active segment
+    fn write_out(&mut self) -> Result<(), Error> {
+        let mut file = &self.segments[&self.active];
+        let result = file
+            .seek(SeekFrom::Start(self.written))
+            .and_then(|_| file.write_all(&self.pending));
+        if let Err(e) = result {
+            // drop anything partially written, entries are still pending
+            let _ = file.set_len(self.written);
+            return Err(Error::Io(e));
+        }
+        self.written += self.pending.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    // Seal the active segment and start a new one, merging full segments if there
+    // are enough of them.
+    fn roll(&mut self) -> Result<(), Error> {
+        self.write_out()?;
+        let id = self.active + 1;
+        let file = create_segment(&segment_path(&self.dir, id), id)?;
+        self.segments.insert(id, file);
+        self.active = id;
+        self.written = HEADER_SIZE;
+        if self.segments.len() > MERGE_THRESHOLD {
+            self.start_merge();
+        }
+        Ok(())
+    }
+
+    fn start_merge(&mut self) {
+        if self.merge.is_some() {
+            return;
+        }
+        let dir = self.dir.clone();
+        let sealed = self
+            .segments
+            .range(..self.active)
+            .map(|(id, _)| *id)
+            .collect();
+        self.merge = Some(std::thread::spawn(move || merge(&dir, sealed)));
+    }
+
+    // Point the index to merged segments once a merge is over, waiting for it if `wait`
+    fn finish_merge(&mut self, wait: bool) -> Result<(), Error> {
+        match &self.merge {
+            Some(handle) if wait || handle.is_finished() => {}
+            _ => return Ok(()),
+        }
+        let merged = self
+            .merge
+            .take()
+            .expect("checked above")
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))?;
+
+        let target = *merged.segments.last().expect("merges are never empty");
+        for (kind, key, from, offset) in merged.moved {
+            let location = match kind {
+                Kind::Tx => self.txs.get_mut(&key),
+                Kind::Fee => self.fees.get_mut(&key),
+            };
+            // otherwise overwritten while merging
+            if let Some(location) = location.filter(|location| **location == from) {
+                *location = Location {
+                    segment: target,
+                    offset,
+                };
+            }
+        }
+        for id in merged.segments {
+            self.segments.remove(&id);
+            if id != target {
+                // left over segments are ignored when opening the store anyway
+                let _ = std::fs::remove_file(segment_path(&self.dir, id));
+            }
+        }
+        self.segments.insert(target, merged.file);
+        Ok(())
+    }
+}
+
+impl Drop for LogStore {
+    fn drop(&mut self) {
+        // do not leave the merge thread writing to a directory about to be removed
+        if let Some(handle) = self.merge.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl TxStore for LogStore {
+    fn insert(&mut self, client: Client, tx_id: TxId, record: TxRecord) -> Result<(), Error> {
+        self.append(compute_id(client, tx_id), Entry::Tx(record))
+    }
+
+    fn get(&self, client: Client, tx_id: TxId) -> Result<TxRecord, Error> {
+        let location = self.txs.get(&compute_id(client, tx_id));
+        self.read_tx(*location.ok_or(Error::NotFound)?)
+    }
+
+    fn remove(&mut self, client: Client, tx_id: TxId) -> Result<(), Error> {
+        let id = compute_id(client, tx_id);
+        if self.txs.contains_key(&id) {
+            self.append(id, Entry::RemoveTx)?;
+        }
+        Ok(())
+    }
+
+    fn scan(
+        &self,
+        clients: RangeInclusive<Client>,
+    ) -> Result<Vec<(Client, TxId, TxRecord)>, Error> {
+        self.txs
+            .range(compute_id(*clients.start(), TxId::MIN)..=compute_id(*clients.end(), TxId::MAX))
+            .map(|(id, location)| {
+                let (client, tx_id) = split_id(*id);
+                Ok((client, tx_id, self.read_tx(*location)?))
+            })
+            .collect()
+    }
+
+    fn insert_fee(&mut self, client: Client, tx_id: TxId, fee: Value) -> Result<(), Error> {
+        self.append(compute_id(client, tx_id), Entry::Fee(fee))
+    }
+
+    fn get_fee(&self, client: Client, tx_id: TxId) -> Result<Value, Error> {
+        let location = self.fees.get(&compute_id(client, tx_id));
+        match self.read(*location.ok_or(Error::NotFound)?)? {
+            Entry::Fee(fee) => Ok(fee),
+            _ => Err(Error::Corrupt("index does not point to a fee")),
+        }
+    }
+
+    fn stats(&self) -> Result<Stats, Error> {
+        let mut stats = Stats {
+            fees: self.fees.len() as u64,
+            ..Stats::default()
+        };
+        for file in self.segments.values() {
+            stats.bytes_on_disk += file.metadata().map_err(Error::Io)?.len();
+        }
+        for (client, _, record) in self.scan(Client::MIN..=Client::MAX)? {
+            stats.count(client, &record);
+        }
+        Ok(stats)
+    }
+
+    /// Merge all the segments written so far.
+    fn compact(&mut self) -> Result<bool, Error> {
+        self.commit_batch()?;
+        self.finish_merge(true)?;
+        if self.written > HEADER_SIZE || !self.pending.is_empty() {
+            self.roll()?;
+        } else if self.segments.len() == 1 {
+            // nothing written at all
+            return Ok(false);
+        }
+        self.start_merge();
+        self.finish_merge(true)?;
+        Ok(true)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.commit_batch()?;
+        self.finish_merge(true)?;
+        self.write_out()?;
+        for file in self.segments.values() {
+            file.sync_all().map_err(Error::Io)?;
+        }
+        Ok(())
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        self.commit_batch()?;
+        self.prepare()?;
+        self.batch = Some(Batch {
+            start: self.pending.len(),
+            undo: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    /// The batch is written out at once if the write buffer is full, and dropped if
+    /// that fails.
+    fn commit_batch(&mut self) -> Result<(), Error> {
+        if self.batch.is_none() {
+            return Ok(());
+        }
+        if self.pending.len() >= WRITE_BUFFER {
+            if let Err(e) = self.write_out() {
+                self.rollback();
+                return Err(e);
+            }
+        }
+        self.batch = None;
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("segment-{id:010}.log"))
+}
+
+fn segment_id(path: &Path) -> Option<u32> {
+    path.file_name()?
+        .to_str()?
+        .strip_prefix("segment-")?
+        .strip_suffix(".log")?
+        .parse()
+        .ok()
+}
+
+fn create_segment(path: &Path, first: u32) -> Result<File, Error> {
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .map_err(Error::Io)?;
+    file.write_all(MAGIC).map_err(Error::Io)?;
+    file.write_all(&first.to_be_bytes()).map_err(Error::Io)?;
+    Ok(file)
+}
+
+fn read_header(file: &mut File) -> Result<u32, Error> {
+    let mut header = [0; HEADER_SIZE as usize];
+    file.seek(SeekFrom::Start(0)).map_err(Error::Io)?;
+    file.read_exact(&mut header)
+        .map_err(|_| Error::Corrupt("truncated segment header"))?;
+    if &header[..8] != MAGIC {
+        return Err(Error::Corrupt("not a log segment"));
+    }
+    Ok(u32::from_be_bytes(header[8..].try_into().expect("4 bytes")))
+}
+
+// All complete entries of a segment along with their offsets. An incomplete one
+// at the end, left by an interrupted write, is dropped from the file.
+fn read_entries(file: &mut File) -> Result<Vec<(u64, Vec<u8>)>, Error> {
+    let mut data = Vec::new();
+    file.seek(SeekFrom::Start(HEADER_SIZE)).map_err(Error::Io)?;
+    file.read_to_end(&mut data).map_err(Error::Io)?;
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        match entry_len(&data[pos..]) {
+            Ok(len) if pos + len <= data.len() => {
+                entries.push((HEADER_SIZE + pos as u64, data[pos..pos + len].to_vec()));
+                pos += len;
+            }
+            _ => {
+                file.set_len(HEADER_SIZE + pos as u64).map_err(Error::Io)?;
+                break;
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// Size of the entry starting at `bytes`, which must hold at least its header
+fn entry_len(bytes: &[u8]) -> Result<usize, Error> {
+    match bytes.get(9..ENTRY_HEADER) {
+        Some(len) => Ok(ENTRY_HEADER + u16::from_be_bytes([len[0], len[1]]) as usize),
+        None => Err(Error::Corrupt("truncated entry")),
+    }
+}
+
+fn encode(key: u64, entry: &Entry) -> Vec<u8> {
+    let (tag, payload) = match entry {
+        Entry::Tx(record) => (TX, record.encode()),
+        Entry::Fee(fee) => (FEE, fee.serialize().to_vec()),
+        Entry::RemoveTx => (REMOVE_TX, Vec::new()),
+    };
+    let mut bytes = Vec::with_capacity(ENTRY_HEADER + payload.len());
+    bytes.push(tag);
+    bytes.extend_from_slice(&key.to_be_bytes());
+    bytes.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Result<(u64, Entry), Error> {
+    if bytes.len() < ENTRY_HEADER {
+        return Err(Error::Corrupt("truncated entry"));
+    }
+    let key = u64::from_be_bytes(bytes[1..9].try_into().expect("8 bytes"));
+    let payload = &bytes[ENTRY_HEADER..];
+    let entry = match bytes[0] {
+        TX => Entry::Tx(TxRecord::decode(payload)?),
+        FEE => Entry::Fee(Value::deserialize(
+            payload
+                .try_into()
+                .map_err(|_| Error::Corrupt("invalid fee length"))?,
+        )),
+        REMOVE_TX => Entry::RemoveTx,
+        _ => return Err(Error::Corrupt("invalid entry tag")),
+    };
+    Ok((key, entry))
+}
+
+// Rewrite the last version of every record in `segments` to a single segment,
+// which replaces the last one of them.
+fn merge(dir: &Path, segments: Vec<u32>) -> Result<Merged, Error> {
+    let target = *segments.last().expect("merges are never empty");
+    let mut first = target;
+    let mut live = BTreeMap::new();
+    for &id in &segments {
+        let mut file = File::open(segment_path(dir, id)).map_err(Error::Io)?;
+        first = first.min(read_header(&mut file)?);
+        for (offset, bytes) in read_entries(&mut file)? {
+            let location = Location {
+                segment: id,
+                offset,
+            };
+            match decode(&bytes)? {
+                (key, Entry::Tx(_)) => live.insert((Kind::Tx, key), (location, bytes)),
+                (key, Entry::Fee(_)) => live.insert((Kind::Fee, key), (location, bytes)),
+                (key, Entry::RemoveTx) => live.remove(&(Kind::Tx, key)),
+            };
+        }
+    }
+
+    let mut data = Vec::new();
+    let mut moved = Vec::with_capacity(live.len());
+    for ((kind, key), (location, bytes)) in live {
+        let offset = HEADER_SIZE + data.len() as u64;
+        moved.push((kind, key, location, offset));
+        data.extend_from_slice(&bytes);
+    }
+
+    let path = segment_path(dir, target);
+    let merging = path.with_extension("merging");
+    let mut file = create_segment(&merging, first)?;
+    file.write_all(&data).map_err(Error::Io)?;
+    file.sync_all().map_err(Error::Io)?;
+    std::fs::rename(&merging, &path).map_err(Error::Io)?;
+    Ok(Merged {
+        segments,
+        file,
+        moved,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::record;
+    use super::*;
+
+    fn entry_size() -> u32 {
+        encode(0, &Entry::Tx(record(0))).len() as u32
+    }
+
+    fn segment_files(dir: &Path) -> usize {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .filter(|entry| segment_id(&entry.as_ref().unwrap().path()).is_some())
+            .count()
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut store = LogStore::new()
+            .unwrap()
+            .segment_size(HEADER_SIZE as u32 + 10 * entry_size());
+        for tx_id in 0..200 {
+            store.insert(1, tx_id % 50, record(tx_id as i64)).unwrap();
+            if tx_id % 3 == 0 {
+                store.remove(1, tx_id % 50).unwrap();
+            }
+        }
+        store.insert_fee(1, 0, Value::ONE).unwrap();
+        let expected = store.scan(1..=1).unwrap();
+        let stats = store.stats().unwrap();
+
+        store.compact().unwrap();
+        assert_eq!(store.segments.len(), 2);
+        assert_eq!(segment_files(&store.dir), 2);
+        let compacted = store.stats().unwrap();
+        assert!(compacted.bytes_on_disk < stats.bytes_on_disk);
+        assert_eq!(compacted.undisputed, stats.undisputed);
+        assert_eq!(
+            store
+                .scan(1..=1)
+                .unwrap()
+                .iter()
+                .map(|(_, tx_id, r)| (*tx_id, r.value))
+                .collect::<Vec<_>>(),
+            expected
+                .iter()
+                .map(|(_, tx_id, r)| (*tx_id, r.value))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(store.get_fee(1, 0).unwrap(), Value::ONE);
+    }
+
+    #[test]
+    fn test_compact_empty_segment() {
+        let mut store = LogStore::new().unwrap();
+        assert!(!store.compact().unwrap());
+        assert_eq!(store.segments.len(), 1);
+
+        store.insert(1, 0, record(0)).unwrap();
+        assert!(store.compact().unwrap());
+        let active = store.active;
+        assert!(store.compact().unwrap());
+        assert_eq!(store.active, active);
+        assert_eq!(segment_files(&store.dir), 2);
+        assert_eq!(store.get(1, 0).unwrap().value, Value::ZERO);
+    }
+
+    #[test]
+    fn test_failed_batch() {
+        let mut store = LogStore::new().unwrap();
+        store.insert(1, 0, record(0)).unwrap();
+        store.insert_fee(1, 0, Value::ONE).unwrap();
+        let pending = store.pending.len();
+
+        store.begin_batch().unwrap();
+        store.insert(1, 0, record(1)).unwrap();
+        store.insert_fee(1, 0, Value::TWO).unwrap();
+        // enough to fill the write buffer
+        for tx_id in 1..=WRITE_BUFFER as u32 / entry_size() {
+            store.insert(1, tx_id, record(tx_id as i64)).unwrap();
+        }
+        store.remove(1, 1).unwrap();
+        assert_eq!(store.get(1, 0).unwrap().value, Value::ONE);
+        assert!(store.get(1, 1).is_err());
+
+        let path = segment_path(&store.dir, store.active);
+        let active = store.segments.insert(store.active, File::open(&path).unwrap());
+        assert!(store.commit_batch().is_err());
+        assert_eq!(store.pending.len(), pending);
+        assert_eq!(store.get(1, 0).unwrap().value, Value::ZERO);
+        assert_eq!(store.get_fee(1, 0).unwrap(), Value::ONE);
+        assert!(store.get(1, 2).is_err());
+        assert_eq!(store.scan(1..=1).unwrap().len(), 1);
+
+        store.segments.insert(store.active, active.unwrap());
+        store.begin_batch().unwrap();
+        store.insert(1, 1, record(1)).unwrap();
+        store.commit_batch().unwrap();
+        store.flush().unwrap();
+        assert_eq!(store.scan(1..=1).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            // small enough to roll segments, but not to start merging them
+            let mut store = LogStore::open(dir.path())
+                .unwrap()
+                .segment_size(HEADER_SIZE as u32 + 3 * entry_size());
+            store.insert(1, 0, record(0)).unwrap();
+            store.remove(1, 0).unwrap();
+            for tx_id in 1..10 {
+                store.insert(1, tx_id, record(tx_id as i64)).unwrap();
+            }
+            store.flush().unwrap();
+            assert_eq!(store.segments.len(), 4);
+        }
+        // a merge interrupted before removing the segments it replaced
+        merge(dir.path(), vec![0, 1]).unwrap();
+        // and a torn write
+        let mut file = File::options()
+            .append(true)
+            .open(segment_path(dir.path(), 3))
+            .unwrap();
+        file.write_all(&[TX; 3]).unwrap();
+
+        let store = LogStore::open(dir.path()).unwrap();
+        assert!(matches!(store.get(1, 0), Err(Error::NotFound)));
+        assert_eq!(store.scan(1..=1).unwrap().len(), 9);
+        assert_eq!(store.get(1, 9).unwrap().value, Value::new(9, 0));
+        assert!(!segment_path(dir.path(), 0).exists());
+        assert_eq!(segment_files(dir.path()), 4);
+    }
+}
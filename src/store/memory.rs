@@ -0,0 +1,69 @@
+use super::{Error, Stats, TxRecord, TxStore};
+use crate::common::*;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// Transaction store keeping everything in memory.
+///
+/// Fine for small jobs and tests, the whole history has to fit in memory.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    txs: HashMap<(Client, TxId), TxRecord>,
+    fees: HashMap<(Client, TxId), Value>,
+}
+
+impl TxStore for MemoryStore {
+    fn insert(&mut self, client: Client, tx_id: TxId, record: TxRecord) -> Result<(), Error> {
+        self.txs.insert((client, tx_id), record);
+        Ok(())
+    }
+
+    fn get(&self, client: Client, tx_id: TxId) -> Result<TxRecord, Error> {
+        self.txs
+            .get(&(client, tx_id))
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    fn remove(&mut self, client: Client, tx_id: TxId) -> Result<(), Error> {
+        self.txs.remove(&(client, tx_id));
+        Ok(())
+    }
+
+    fn scan(
+        &self,
+        clients: RangeInclusive<Client>,
+    ) -> Result<Vec<(Client, TxId, TxRecord)>, Error> {
+        let mut txs = self
+            .txs
+            .iter()
+            .filter(|((client, _), _)| clients.contains(client))
+            .map(|((client, tx_id), record)| (*client, *tx_id, record.clone()))
+            .collect::<Vec<_>>();
+        txs.sort_unstable_by_key(|(client, tx_id, _)| (*client, *tx_id));
+        Ok(txs)
+    }
+
+    fn insert_fee(&mut self, client: Client, tx_id: TxId, fee: Value) -> Result<(), Error> {
+        self.fees.insert((client, tx_id), fee);
+        Ok(())
+    }
+
+    fn get_fee(&self, client: Client, tx_id: TxId) -> Result<Value, Error> {
+        self.fees
+            .get(&(client, tx_id))
+            .copied()
+            .ok_or(Error::NotFound)
+    }
+
+    fn stats(&self) -> Result<Stats, Error> {
+        let mut stats = Stats {
+            fees: self.fees.len() as u64,
+            ..Stats::default()
+        };
+        for ((client, _), record) in &self.txs {
+            stats.count(*client, record);
+        }
+        Ok(stats)
+    }
+}
@@ -0,0 +1,396 @@
+use super::common::*;
+use super::transaction::TxStatus;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::Arc;
+use thiserror::Error;
+
+mod db;
+mod log;
+mod memory;
+mod record;
+mod tiered;
+
+pub use db::{Check, TransactionStore};
+pub use log::LogStore;
+pub use memory::MemoryStore;
+pub use record::TxRecord;
+pub use tiered::TieredStore;
+
+/// Keep a record of validated transactions to process disputes.
+///
+/// Each worker owns its store, so implementations do not need to support concurrent access.
+/// [`TransactionStore`] keeps records on disk and should be preferred for large inputs,
+/// [`MemoryStore`] is faster but everything has to fit in memory.
+/// [`LogStore`] is an alternative on disk store, tailored to the engine access pattern.
+pub trait TxStore: Send + std::fmt::Debug {
+    /// Insert a new transaction, replacing any previous record for the same id.
+    fn insert(&mut self, client: Client, tx_id: TxId, record: TxRecord) -> Result<(), Error>;
+
+    /// Fetch a transaction
+    fn get(&self, client: Client, tx_id: TxId) -> Result<TxRecord, Error>;
+
+    fn remove(&mut self, client: Client, tx_id: TxId) -> Result<(), Error>;
+
+    /// All transactions of `clients`, in client and tx id order
+    fn scan(&self, clients: RangeInclusive<Client>)
+        -> Result<Vec<(Client, TxId, TxRecord)>, Error>;
+
+    /// Record a fee charged to a client as part of a transaction.
+    fn insert_fee(&mut self, client: Client, tx_id: TxId, fee: Value) -> Result<(), Error>;
+
+    /// Fetch the fee charged as part of a transaction
+    fn get_fee(&self, client: Client, tx_id: TxId) -> Result<Value, Error>;
+
+    /// All transactions stored for `client`, in tx id order
+    fn client_history(&self, client: Client) -> Result<Vec<(TxId, TxRecord)>, Error> {
+        Ok(self
+            .scan(client..=client)?
+            .into_iter()
+            .map(|(_, tx_id, record)| (tx_id, record))
+            .collect())
+    }
+
+    /// Count stored records and measure the space they take.
+    ///
+    /// The default implementation only looks at transactions.
+    fn stats(&self) -> Result<Stats, Error> {
+        let mut stats = Stats::default();
+        for (client, _, record) in self.scan(Client::MIN..=Client::MAX)? {
+            stats.count(client, &record);
+        }
+        Ok(stats)
+    }
+
+    /// Reclaim space left by removed records.
+    ///
+    /// Returns whether the store was compacted.
+    fn compact(&mut self) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    /// Make all previous writes durable
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Group the following writes until [`Self::commit_batch`] is called.
+    ///
+    /// Reads in the meantime see the writes of the batch.
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Apply all the writes of the current batch.
+    ///
+    /// Either all of them are applied, or none of them if an error is returned.
+    fn commit_batch(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Summary of the store content
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    pub undisputed: u64,
+    pub disputed: u64,
+    pub resolved: u64,
+    pub charged_back: u64,
+    pub represented: u64,
+    pub fees: u64,
+    pub bytes_on_disk: u64,
+    /// Lookups served from memory, only counted by [`TieredStore`]
+    pub hits: u64,
+    /// Lookups that had to go to disk, only counted by [`TieredStore`]
+    pub misses: u64,
+    /// Transaction records per client
+    #[serde(skip)]
+    pub clients: BTreeMap<Client, u64>,
+}
+
+impl Stats {
+    /// Combine stats from different stores
+    pub fn merge(&mut self, other: Stats) {
+        self.undisputed += other.undisputed;
+        self.disputed += other.disputed;
+        self.resolved += other.resolved;
+        self.charged_back += other.charged_back;
+        self.represented += other.represented;
+        self.fees += other.fees;
+        self.bytes_on_disk += other.bytes_on_disk;
+        self.hits += other.hits;
+        self.misses += other.misses;
+        for (client, count) in other.clients {
+            *self.clients.entry(client).or_default() += count;
+        }
+    }
+
+    fn count(&mut self, client: Client, record: &TxRecord) {
+        match record.status {
+            TxStatus::Undisputed => self.undisputed += 1,
+            TxStatus::Disputed => self.disputed += 1,
+            TxStatus::Resolved => self.resolved += 1,
+            TxStatus::ChargedBack => self.charged_back += 1,
+            TxStatus::Represented => self.represented += 1,
+        }
+        *self.clients.entry(client).or_default() += 1;
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("not found")]
+    NotFound,
+    #[error(transparent)]
+    Db(Box<redb::Error>),
+    #[error("error creating temporary file")]
+    TempFile(std::io::Error),
+    #[error("error accessing store directory")]
+    Io(std::io::Error),
+    #[error("corrupted store: {0}")]
+    Corrupt(&'static str),
+    #[error("record encoded with unsupported version {0}")]
+    Version(u8),
+}
+
+// db methods return individual errors, instead of exposing all the redb errors
+// to the user return onnly a top level one
+impl<E> From<E> for Error
+where
+    redb::Error: From<E>,
+{
+    fn from(e: E) -> Self {
+        Self::Db(Box::new(redb::Error::from(e)))
+    }
+}
+
+/// Builds the store of a worker given its id
+pub type StoreFactory = Arc<dyn Fn(usize) -> Result<Box<dyn TxStore>, Error> + Send + Sync>;
+
+/// Which store workers keep their transactions in
+#[derive(Clone)]
+pub enum Backend {
+    /// Everything in memory, for small jobs
+    Memory,
+    /// redb, in the given directory or in temporary files if missing
+    Redb(Option<PathBuf>),
+    /// [`LogStore`], in the given directory or in temporary ones if missing
+    Log(Option<PathBuf>),
+    /// Recent records in memory, up to `budget` bytes, older ones in `cold`
+    Tiered { budget: usize, cold: Box<Backend> },
+    /// User provided store
+    Custom(StoreFactory),
+}
+
+impl std::fmt::Debug for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Memory => f.write_str("Memory"),
+            Self::Redb(dir) => f.debug_tuple("Redb").field(dir).finish(),
+            Self::Log(dir) => f.debug_tuple("Log").field(dir).finish(),
+            Self::Tiered { budget, cold } => f
+                .debug_struct("Tiered")
+                .field("budget", budget)
+                .field("cold", cold)
+                .finish(),
+            Self::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::Redb(None)
+    }
+}
+
+impl Backend {
+    /// Open the store for the worker with the given id
+    pub fn open(&self, worker: usize) -> Result<Box<dyn TxStore>, Error> {
+        Ok(match self {
+            Self::Memory => Box::<MemoryStore>::default(),
+            Self::Redb(None) => Box::new(TransactionStore::new()?),
+            Self::Redb(Some(dir)) => Box::new(TransactionStore::open_shard(dir, worker)?),
+            Self::Log(None) => Box::new(LogStore::new()?),
+            Self::Log(Some(dir)) => Box::new(LogStore::open_shard(dir, worker)?),
+            Self::Tiered { budget, cold } => {
+                Box::new(TieredStore::new(*budget, cold.open(worker)?))
+            }
+            Self::Custom(factory) => factory(worker)?,
+        })
+    }
+}
+
+fn compute_id(client: Client, tx_id: TxId) -> u64 {
+    // Use the client id as the high bits and the tx id as the low bits.
+    // This allows us to do prefix queries on clients.
+    ((client as u64) << 32) | (tx_id as u64)
+}
+
+fn split_id(id: u64) -> (Client, TxId) {
+    ((id >> 32) as Client, id as TxId)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::transaction::TxKind;
+
+    pub(super) fn record(value: i64) -> TxRecord {
+        TxRecord::new(TxKind::Deposit, Value::new(value, 0), 0)
+    }
+
+    /// A record disputed for its whole value
+    pub(super) fn disputed(value: i64) -> TxRecord {
+        let record = record(value);
+        TxRecord {
+            disputable: Value::ZERO,
+            held: record.value,
+            ..record.with_status(TxStatus::Disputed, 1)
+        }
+    }
+
+    /// How a [`FaultyStore`] fails
+    #[derive(Debug, Default, Clone, Copy, PartialEq)]
+    pub(crate) enum Fault {
+        /// Batches are discarded on commit, which fails
+        #[default]
+        Commit,
+        /// Panics as soon as a transaction is stored
+        Panic,
+    }
+
+    /// Store in memory failing as told, to check how failures are handled
+    #[derive(Debug, Default)]
+    pub(crate) struct FaultyStore {
+        inner: MemoryStore,
+        snapshot: Option<MemoryStore>,
+        fault: Fault,
+    }
+
+    impl FaultyStore {
+        pub(crate) fn new(fault: Fault) -> Self {
+            Self {
+                fault,
+                ..Self::default()
+            }
+        }
+    }
+
+    impl TxStore for FaultyStore {
+        fn insert(&mut self, client: Client, tx_id: TxId, record: TxRecord) -> Result<(), Error> {
+            if self.fault == Fault::Panic {
+                panic!("disk on fire");
+            }
+            self.inner.insert(client, tx_id, record)
+        }
+
+        fn get(&self, client: Client, tx_id: TxId) -> Result<TxRecord, Error> {
+            self.inner.get(client, tx_id)
+        }
+
+        fn remove(&mut self, client: Client, tx_id: TxId) -> Result<(), Error> {
+            self.inner.remove(client, tx_id)
+        }
+
+        fn scan(
+            &self,
+            clients: RangeInclusive<Client>,
+        ) -> Result<Vec<(Client, TxId, TxRecord)>, Error> {
+            self.inner.scan(clients)
+        }
+
+        fn insert_fee(&mut self, client: Client, tx_id: TxId, fee: Value) -> Result<(), Error> {
+            self.inner.insert_fee(client, tx_id, fee)
+        }
+
+        fn get_fee(&self, client: Client, tx_id: TxId) -> Result<Value, Error> {
+            self.inner.get_fee(client, tx_id)
+        }
+
+        fn begin_batch(&mut self) -> Result<(), Error> {
+            self.snapshot = Some(self.inner.clone());
+            Ok(())
+        }
+
+        fn commit_batch(&mut self) -> Result<(), Error> {
+            let snapshot = self.snapshot.take().unwrap_or_default();
+            match self.fault {
+                Fault::Commit => {
+                    self.inner = snapshot;
+                    Err(Error::Io(std::io::Error::other("commit failed")))
+                }
+                Fault::Panic => Ok(()),
+            }
+        }
+    }
+
+    fn check_store(store: &mut dyn TxStore) {
+        assert!(matches!(store.get(1, 1), Err(Error::NotFound)));
+        assert!(store.client_history(1).unwrap().is_empty());
+        store.insert(1, 3, record(3)).unwrap();
+        store.insert(2, 2, record(2)).unwrap();
+        store.insert(1, 1, record(1)).unwrap();
+        store.insert(0, 4, record(4)).unwrap();
+        assert_eq!(store.get(1, 3).unwrap().value, Value::new(3, 0));
+        assert_eq!(
+            store
+                .client_history(1)
+                .unwrap()
+                .iter()
+                .map(|(id, r)| (*id, r.value))
+                .collect::<Vec<_>>(),
+            vec![(1, Value::ONE), (3, Value::new(3, 0))]
+        );
+        assert_eq!(
+            store
+                .scan(1..=2)
+                .unwrap()
+                .iter()
+                .map(|(client, id, _)| (*client, *id))
+                .collect::<Vec<_>>(),
+            vec![(1, 1), (1, 3), (2, 2)]
+        );
+
+        store.remove(1, 3).unwrap();
+        assert!(matches!(store.get(1, 3), Err(Error::NotFound)));
+        store.insert_fee(1, 1, Value::TWO).unwrap();
+        assert_eq!(store.get_fee(1, 1).unwrap(), Value::TWO);
+
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.undisputed, 3);
+        assert_eq!(stats.clients, BTreeMap::from([(0, 1), (1, 1), (2, 1)]));
+    }
+
+    #[test]
+    fn test_memory_store() {
+        check_store(&mut MemoryStore::default());
+    }
+
+    #[test]
+    fn test_redb_store() {
+        check_store(&mut TransactionStore::new().unwrap());
+    }
+
+    #[test]
+    fn test_log_store() {
+        check_store(&mut LogStore::new().unwrap());
+    }
+
+    #[test]
+    fn test_tiered_store() {
+        let backend = Backend::Tiered {
+            budget: 100,
+            cold: Box::new(Backend::Redb(None)),
+        };
+        check_store(backend.open(0).unwrap().as_mut());
+    }
+
+    #[test]
+    fn test_custom_backend() {
+        let backend = Backend::Custom(Arc::new(|_| Ok(Box::<MemoryStore>::default())));
+        check_store(backend.open(0).unwrap().as_mut());
+    }
+}
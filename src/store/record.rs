@@ -0,0 +1,272 @@
+use super::Error;
+use crate::common::*;
+use crate::transaction::{TxKind, TxStatus};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Stored records start with the version of their encoding, so that fields can be added
+// without rewriting existing stores. Decoders of previous versions fill in defaults.
+//
+// Version 1: version, value (16), status, kind, seq (8), created_at (8), updated_at (8)
+// Version 2: version 1 fields, updated_seq (8)
+// Version 3: version 2 fields, disputable (16), held (16), charged_back (16)
+const VERSION: u8 = 3;
+const V1_SIZE: usize = 43;
+const V2_SIZE: usize = 51;
+const V3_SIZE: usize = 99;
+// Before versioning: value (16), status
+const LEGACY_SIZE: usize = 17;
+
+/// A transaction kept around to process disputes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TxRecord {
+    pub value: Value,
+    pub status: TxStatus,
+    /// Position of the transaction in the engine input
+    pub seq: u64,
+    pub kind: TxKind,
+    /// Milliseconds since the Unix epoch
+    pub created_at: u64,
+    /// Milliseconds since the Unix epoch of the last status change
+    pub updated_at: u64,
+    /// Position in the engine input of the transaction which last changed the status
+    pub updated_seq: u64,
+    /// Part of the value which can still be disputed
+    pub disputable: Value,
+    /// Part of the value currently held by disputes
+    pub held: Value,
+    /// Part of the value charged back
+    pub charged_back: Value,
+}
+
+impl TxRecord {
+    /// A new undisputed record, created now
+    pub fn new(kind: TxKind, value: Value, seq: u64) -> Self {
+        let now = now();
+        Self {
+            value,
+            status: TxStatus::Undisputed,
+            seq,
+            kind,
+            created_at: now,
+            updated_at: now,
+            updated_seq: seq,
+            disputable: value,
+            held: Value::ZERO,
+            charged_back: Value::ZERO,
+        }
+    }
+
+    /// The same record, moved to `status` now by the transaction at `seq` in the input
+    pub fn with_status(&self, status: TxStatus, seq: u64) -> Self {
+        Self {
+            status,
+            updated_at: now(),
+            updated_seq: seq,
+            ..self.clone()
+        }
+    }
+
+    /// Encode with the latest version
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(V3_SIZE);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.value.serialize());
+        bytes.push(self.status as u8);
+        bytes.push(self.kind as u8);
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        bytes.extend_from_slice(&self.created_at.to_be_bytes());
+        bytes.extend_from_slice(&self.updated_at.to_be_bytes());
+        bytes.extend_from_slice(&self.updated_seq.to_be_bytes());
+        bytes.extend_from_slice(&self.disputable.serialize());
+        bytes.extend_from_slice(&self.held.serialize());
+        bytes.extend_from_slice(&self.charged_back.serialize());
+        bytes
+    }
+
+    /// Decode a record encoded with any version up to the latest one
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        match bytes.first() {
+            Some(1) => Self::decode_v1(bytes),
+            Some(2) => Self::decode_v2(bytes),
+            Some(&VERSION) => Self::decode_v3(bytes),
+            Some(&version) => Err(Error::Version(version)),
+            None => Err(Error::Corrupt("empty record")),
+        }
+    }
+
+    /// Decode a record written before encodings were versioned.
+    ///
+    /// Those did not keep track of anything but value and status, other fields are zeroed.
+    pub fn decode_legacy(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != LEGACY_SIZE {
+            return Err(Error::Corrupt("invalid record length"));
+        }
+        Ok(Self {
+            value: Value::deserialize(read(bytes, 0)),
+            status: status(bytes[16])?,
+            seq: 0,
+            kind: TxKind::Deposit,
+            created_at: 0,
+            updated_at: 0,
+            updated_seq: 0,
+            disputable: Value::ZERO,
+            held: Value::ZERO,
+            charged_back: Value::ZERO,
+        }
+        .with_whole_amounts())
+    }
+
+    // Version 1 did not keep track of what changed the status, the creation is assumed
+    fn decode_v1(bytes: &[u8]) -> Result<Self, Error> {
+        // longer records may come from later versions adding fields at the end,
+        // older binaries should not be reading those though
+        if bytes.len() != V1_SIZE {
+            return Err(Error::Corrupt("invalid record length"));
+        }
+        Ok(Self::decode_fields(bytes)?.with_whole_amounts())
+    }
+
+    fn decode_v2(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != V2_SIZE {
+            return Err(Error::Corrupt("invalid record length"));
+        }
+        Ok(Self {
+            updated_seq: u64::from_be_bytes(read(bytes, V1_SIZE)),
+            ..Self::decode_fields(bytes)?
+        }
+        .with_whole_amounts())
+    }
+
+    fn decode_v3(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() != V3_SIZE {
+            return Err(Error::Corrupt("invalid record length"));
+        }
+        Ok(Self {
+            updated_seq: u64::from_be_bytes(read(bytes, V1_SIZE)),
+            disputable: Value::deserialize(read(bytes, V2_SIZE)),
+            held: Value::deserialize(read(bytes, V2_SIZE + 16)),
+            charged_back: Value::deserialize(read(bytes, V2_SIZE + 32)),
+            ..Self::decode_fields(bytes)?
+        })
+    }
+
+    // Fields common to all versions, those added later are left to the caller
+    fn decode_fields(bytes: &[u8]) -> Result<Self, Error> {
+        let seq = u64::from_be_bytes(read(bytes, 19));
+        Ok(Self {
+            value: Value::deserialize(read(bytes, 1)),
+            status: status(bytes[17])?,
+            kind: TxKind::try_from(bytes[18])
+                .map_err(|_| Error::Corrupt("invalid transaction kind"))?,
+            seq,
+            created_at: u64::from_be_bytes(read(bytes, 27)),
+            updated_at: u64::from_be_bytes(read(bytes, 35)),
+            updated_seq: seq,
+            disputable: Value::ZERO,
+            held: Value::ZERO,
+            charged_back: Value::ZERO,
+        })
+    }
+
+    // Before version 3 disputes were always of the whole value, amounts follow from the status
+    fn with_whole_amounts(self) -> Self {
+        let (disputable, held, charged_back) = match self.status {
+            TxStatus::Undisputed => (self.value, Value::ZERO, Value::ZERO),
+            TxStatus::Disputed => (Value::ZERO, self.value, Value::ZERO),
+            TxStatus::Resolved => (Value::ZERO, Value::ZERO, Value::ZERO),
+            TxStatus::ChargedBack | TxStatus::Represented => (Value::ZERO, Value::ZERO, self.value),
+        };
+        Self {
+            disputable,
+            held,
+            charged_back,
+            ..self
+        }
+    }
+}
+
+fn status(byte: u8) -> Result<TxStatus, Error> {
+    TxStatus::try_from(byte).map_err(|_| Error::Corrupt("invalid transaction status"))
+}
+
+// Lengths are checked beforehand
+fn read<const N: usize>(bytes: &[u8], at: usize) -> [u8; N] {
+    bytes[at..at + N].try_into().expect("length checked")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck_macros::quickcheck;
+
+    #[quickcheck]
+    fn test_roundtrip(value: i64, seq: u64, disputed: bool) {
+        let record = TxRecord::new(TxKind::Deposit, Value::new(value, 2), seq);
+        let record = match disputed {
+            true => record.with_status(TxStatus::Disputed, seq.wrapping_add(1)),
+            false => record,
+        };
+        assert_eq!(TxRecord::decode(&record.encode()).unwrap(), record);
+    }
+
+    #[test]
+    fn test_decode_previous_versions() {
+        let record = TxRecord {
+            disputable: Value::ZERO,
+            held: Value::TEN,
+            ..TxRecord::new(TxKind::Deposit, Value::TEN, 3).with_status(TxStatus::Disputed, 5)
+        };
+        let mut bytes = record.encode();
+        bytes.truncate(V2_SIZE);
+        bytes[0] = 2;
+        assert_eq!(TxRecord::decode(&bytes).unwrap(), record);
+        bytes.truncate(V1_SIZE);
+        bytes[0] = 1;
+        let decoded = TxRecord::decode(&bytes).unwrap();
+        assert_eq!(
+            decoded,
+            TxRecord {
+                updated_seq: 3,
+                ..record
+            }
+        );
+    }
+
+    #[quickcheck]
+    fn test_decode_never_panics(bytes: Vec<u8>) {
+        let _ = TxRecord::decode(&bytes);
+        let _ = TxRecord::decode_legacy(&bytes);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut bytes = TxRecord::new(TxKind::Deposit, Value::ONE, 1).encode();
+        bytes[17] = 9;
+        assert!(matches!(TxRecord::decode(&bytes), Err(Error::Corrupt(_))));
+        bytes[0] = 4;
+        assert!(matches!(TxRecord::decode(&bytes), Err(Error::Version(4))));
+        assert!(matches!(
+            TxRecord::decode(&bytes[..1]),
+            Err(Error::Version(4))
+        ));
+        assert!(TxRecord::decode(&[VERSION]).is_err());
+
+        let mut legacy = [0; LEGACY_SIZE];
+        legacy[..16].copy_from_slice(&Value::TEN.serialize());
+        legacy[16] = 1;
+        let record = TxRecord::decode_legacy(&legacy).unwrap();
+        assert_eq!(
+            (record.value, record.status),
+            (Value::TEN, TxStatus::Disputed)
+        );
+        legacy[16] = 9;
+        assert!(TxRecord::decode_legacy(&legacy).is_err());
+    }
+}
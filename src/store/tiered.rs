@@ -0,0 +1,238 @@
+use super::{Error, Stats, TxRecord, TxStore};
+use crate::common::*;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
+
+type Key = (Client, TxId);
+
+// Rough memory footprint of a record in the hot tier, including its entry in the age index
+const ENTRY_SIZE: usize =
+    std::mem::size_of::<(Key, TxRecord, u64)>() + std::mem::size_of::<(u64, Key)>();
+
+/// Keep recently written records in memory and offload older ones to another store.
+///
+/// Disputes usually reference recent transactions, so most lookups should be served from
+/// memory. Records are moved to the cold tier once the hot one exceeds its memory budget,
+/// least recently written first, and are read back from there transparently.
+#[derive(Debug)]
+pub struct TieredStore {
+    hot: HashMap<Key, (TxRecord, u64)>,
+    // hot keys by write order, oldest first
+    age: BTreeMap<u64, Key>,
+    next_age: u64,
+    capacity: usize,
+    cold: Box<dyn TxStore>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+    // Hot entries as they were before the current batch, for those changed in it.
+    // `None` if no batch is in progress.
+    undo: Option<HashMap<Key, Option<(TxRecord, u64)>>>,
+}
+
+impl TieredStore {
+    /// Keep up to about `budget` bytes of records in memory, offload the rest to `cold`
+    pub fn new(budget: usize, cold: Box<dyn TxStore>) -> Self {
+        Self {
+            hot: HashMap::new(),
+            age: BTreeMap::new(),
+            next_age: 0,
+            capacity: budget / ENTRY_SIZE,
+            cold,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+            undo: None,
+        }
+    }
+
+    /// Lookups served from memory
+    pub fn hits(&self) -> u64 {
+        self.hits.get()
+    }
+
+    /// Lookups served from the cold tier
+    pub fn misses(&self) -> u64 {
+        self.misses.get()
+    }
+
+    // Replace the hot entry for `key`, keeping track of the previous one if in a batch
+    fn set_hot(&mut self, key: Key, entry: Option<(TxRecord, u64)>) {
+        if let Some((_, age)) = &entry {
+            self.age.insert(*age, key);
+        }
+        let previous = match entry {
+            Some(entry) => self.hot.insert(key, entry),
+            None => self.hot.remove(&key),
+        };
+        if let Some((_, age)) = &previous {
+            self.age.remove(age);
+        }
+        if let Some(undo) = &mut self.undo {
+            undo.entry(key).or_insert(previous);
+        }
+    }
+
+    // Move the oldest records to the cold tier until the hot one fits its budget
+    fn evict(&mut self, keep: usize) -> Result<(), Error> {
+        while self.hot.len() > keep {
+            let Some((_, key)) = self.age.first_key_value() else {
+                break;
+            };
+            let key = *key;
+            let (record, _) = self.hot[&key].clone();
+            self.cold.insert(key.0, key.1, record)?;
+            self.set_hot(key, None);
+        }
+        Ok(())
+    }
+}
+
+impl TxStore for TieredStore {
+    fn insert(&mut self, client: Client, tx_id: TxId, record: TxRecord) -> Result<(), Error> {
+        let age = self.next_age;
+        self.next_age += 1;
+        self.set_hot((client, tx_id), Some((record, age)));
+        self.evict(self.capacity)
+    }
+
+    fn get(&self, client: Client, tx_id: TxId) -> Result<TxRecord, Error> {
+        if let Some((record, _)) = self.hot.get(&(client, tx_id)) {
+            self.hits.set(self.hits.get() + 1);
+            return Ok(record.clone());
+        }
+        self.misses.set(self.misses.get() + 1);
+        self.cold.get(client, tx_id)
+    }
+
+    fn remove(&mut self, client: Client, tx_id: TxId) -> Result<(), Error> {
+        // an older version may have been evicted before the record was written again
+        self.cold.remove(client, tx_id)?;
+        self.set_hot((client, tx_id), None);
+        Ok(())
+    }
+
+    fn scan(
+        &self,
+        clients: RangeInclusive<Client>,
+    ) -> Result<Vec<(Client, TxId, TxRecord)>, Error> {
+        // hot records are more recent than their cold copies, if any
+        let mut txs = self
+            .cold
+            .scan(clients.clone())?
+            .into_iter()
+            .map(|(client, tx_id, record)| ((client, tx_id), record))
+            .collect::<BTreeMap<_, _>>();
+        txs.extend(
+            self.hot
+                .iter()
+                .filter(|((client, _), _)| clients.contains(client))
+                .map(|(key, (record, _))| (*key, record.clone())),
+        );
+        Ok(txs
+            .into_iter()
+            .map(|((client, tx_id), record)| (client, tx_id, record))
+            .collect())
+    }
+
+    fn insert_fee(&mut self, client: Client, tx_id: TxId, fee: Value) -> Result<(), Error> {
+        self.cold.insert_fee(client, tx_id, fee)
+    }
+
+    fn get_fee(&self, client: Client, tx_id: TxId) -> Result<Value, Error> {
+        self.cold.get_fee(client, tx_id)
+    }
+
+    fn stats(&self) -> Result<Stats, Error> {
+        let cold = self.cold.stats()?;
+        let mut stats = Stats {
+            fees: cold.fees,
+            bytes_on_disk: cold.bytes_on_disk,
+            hits: self.hits(),
+            misses: self.misses(),
+            ..Stats::default()
+        };
+        for (client, _, record) in self.scan(Client::MIN..=Client::MAX)? {
+            stats.count(client, &record);
+        }
+        Ok(stats)
+    }
+
+    fn compact(&mut self) -> Result<bool, Error> {
+        self.cold.compact()
+    }
+
+    /// Everything is moved to the cold tier to be persisted.
+    fn flush(&mut self) -> Result<(), Error> {
+        self.commit_batch()?;
+        self.evict(0)?;
+        self.cold.flush()
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        self.cold.begin_batch()?;
+        self.undo = Some(HashMap::new());
+        Ok(())
+    }
+
+    fn commit_batch(&mut self) -> Result<(), Error> {
+        let undo = self.undo.take().unwrap_or_default();
+        if let Err(e) = self.cold.commit_batch() {
+            // The cold tier rolled back, including records evicted during the batch,
+            // put those back in memory.
+            for (key, entry) in undo {
+                self.set_hot(key, entry);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{
+        tests::{record, FaultyStore},
+        MemoryStore,
+    };
+    use super::*;
+
+    #[test]
+    fn test_eviction() {
+        let mut store = TieredStore::new(2 * ENTRY_SIZE, Box::<MemoryStore>::default());
+        for tx_id in 0..4 {
+            store.insert(1, tx_id, record(tx_id as i64)).unwrap();
+        }
+        assert_eq!(store.hot.len(), 2);
+        assert_eq!(store.cold.scan(1..=1).unwrap().len(), 2);
+
+        assert_eq!(store.get(1, 3).unwrap().value, Value::new(3, 0));
+        assert_eq!(store.get(1, 0).unwrap().value, Value::ZERO);
+        assert_eq!((store.hits(), store.misses()), (1, 1));
+
+        // writing an evicted record brings it back to memory
+        store.insert(1, 0, record(5)).unwrap();
+        assert_eq!(store.get(1, 0).unwrap().value, Value::new(5, 0));
+        assert_eq!(store.scan(1..=1).unwrap().len(), 4);
+        assert_eq!(store.scan(1..=1).unwrap()[0].2.value, Value::new(5, 0));
+        store.remove(1, 0).unwrap();
+        assert!(store.get(1, 0).is_err());
+
+        store.flush().unwrap();
+        assert!(store.hot.is_empty());
+        assert_eq!(store.stats().unwrap().undisputed, 3);
+    }
+
+    #[test]
+    fn test_failed_batch_restores_hot_tier() {
+        let mut store = TieredStore::new(ENTRY_SIZE, Box::<FaultyStore>::default());
+        store.insert(1, 0, record(0)).unwrap();
+        store.begin_batch().unwrap();
+        store.insert(1, 1, record(1)).unwrap();
+        store.insert(1, 2, record(2)).unwrap();
+        assert!(store.commit_batch().is_err());
+        assert_eq!(store.get(1, 0).unwrap().value, Value::ZERO);
+        assert!(store.get(1, 1).is_err());
+        assert!(store.get(1, 2).is_err());
+        assert_eq!(store.age.len(), 1);
+    }
+}
@@ -18,15 +18,26 @@ pub enum Transaction {
     Dispute {
         tx_id: TxId,
         client: Client,
+        /// Part of the transaction disputed, all that can still be if `None`
+        #[serde(default)]
+        value: Option<Value>,
     },
     Resolve {
         tx_id: TxId,
         client: Client,
+        /// Part of the held funds released, all of them if `None`
+        #[serde(default)]
+        value: Option<Value>,
     },
     Chargeback {
         tx_id: TxId,
         client: Client,
+        /// Part of the held funds charged back, all of them if `None`
+        #[serde(default)]
+        value: Option<Value>,
     },
+    /// The merchant re-presented a charged back transaction, winning its funds back
+    Representment { tx_id: TxId, client: Client },
 }
 
 impl Transaction {
@@ -36,19 +47,70 @@ impl Transaction {
             | Self::Withdrawal { client, .. }
             | Self::Dispute { client, .. }
             | Self::Resolve { client, .. }
-            | Self::Chargeback { client, .. } => *client,
+            | Self::Chargeback { client, .. }
+            | Self::Representment { client, .. } => *client,
+        }
+    }
+
+    pub fn tx_id(&self) -> TxId {
+        match self {
+            Self::Deposit { tx_id, .. }
+            | Self::Withdrawal { tx_id, .. }
+            | Self::Dispute { tx_id, .. }
+            | Self::Resolve { tx_id, .. }
+            | Self::Chargeback { tx_id, .. }
+            | Self::Representment { tx_id, .. } => *tx_id,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum TxStatus {
     Undisputed = 0,
     Disputed = 1,
-    // In theory there are more possible status for transactions: Undisputed, Disputed and Resolved/ChargedBack, but assuming
-    // transactions cannot be disputed more than once, we can remove already resolved transactions from the db
-    // since we're not going to need them anymore
+    Resolved = 2,
+    ChargedBack = 3,
+    /// Charged back, then re-presented
+    Represented = 4,
+    // Resolved and charged back transactions are kept in the db, so that further disputes of
+    // those can be told apart from disputes of unknown transactions. Charged back ones may also
+    // be re-presented.
+}
+
+impl TryFrom<u8> for TxStatus {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0 => Ok(Self::Undisputed),
+            1 => Ok(Self::Disputed),
+            2 => Ok(Self::Resolved),
+            3 => Ok(Self::ChargedBack),
+            4 => Ok(Self::Represented),
+            other => Err(other),
+        }
+    }
+}
+
+/// Which kind of transaction a stored record comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum TxKind {
+    Deposit = 0,
+    Withdrawal = 1,
+}
+
+impl TryFrom<u8> for TxKind {
+    type Error = u8;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        match byte {
+            0 => Ok(Self::Deposit),
+            1 => Ok(Self::Withdrawal),
+            other => Err(other),
+        }
+    }
 }
 
 // A bit annoying, internally tagged enums do not work with csv.
@@ -57,7 +119,7 @@ pub enum TxStatus {
 // our type.
 pub mod serde {
     use super::*;
-    #[derive(Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug)]
     pub struct TransactionCompatCsv {
         #[serde(rename = "type")]
         kind: TType,
@@ -66,7 +128,7 @@ pub mod serde {
         #[serde(alias = "value")] // TODO: remove
         amount: Option<Value>,
     }
-    #[derive(Deserialize, Debug, Copy, Clone)]
+    #[derive(Serialize, Deserialize, Debug, Copy, Clone)]
     #[serde(rename_all = "lowercase")]
     enum TType {
         Deposit,
@@ -74,6 +136,7 @@ pub mod serde {
         Dispute,
         Resolve,
         Chargeback,
+        Representment,
     }
 
     impl TryFrom<TransactionCompatCsv> for Transaction {
@@ -90,15 +153,29 @@ pub mod serde {
                     tx_id: tx.tx,
                     value,
                 }),
-                (TType::Dispute, None) => Ok(Self::Dispute {
-                    client: tx.client,
-                    tx_id: tx.tx,
-                }),
-                (TType::Resolve, None) => Ok(Self::Resolve {
-                    client: tx.client,
-                    tx_id: tx.tx,
-                }),
-                (TType::Chargeback, None) => Ok(Self::Chargeback {
+                // disputes and the like optionally apply to part of the transaction
+                (TType::Dispute, value) if value.is_none_or(|v| v >= Value::ZERO) => {
+                    Ok(Self::Dispute {
+                        client: tx.client,
+                        tx_id: tx.tx,
+                        value,
+                    })
+                }
+                (TType::Resolve, value) if value.is_none_or(|v| v >= Value::ZERO) => {
+                    Ok(Self::Resolve {
+                        client: tx.client,
+                        tx_id: tx.tx,
+                        value,
+                    })
+                }
+                (TType::Chargeback, value) if value.is_none_or(|v| v >= Value::ZERO) => {
+                    Ok(Self::Chargeback {
+                        client: tx.client,
+                        tx_id: tx.tx,
+                        value,
+                    })
+                }
+                (TType::Representment, None) => Ok(Self::Representment {
                     client: tx.client,
                     tx_id: tx.tx,
                 }),
@@ -110,6 +187,25 @@ pub mod serde {
             }
         }
     }
+
+    impl From<&Transaction> for TransactionCompatCsv {
+        fn from(tx: &Transaction) -> Self {
+            let (kind, amount) = match tx {
+                Transaction::Deposit { value, .. } => (TType::Deposit, Some(*value)),
+                Transaction::Withdrawal { value, .. } => (TType::Withdrawal, Some(*value)),
+                Transaction::Dispute { value, .. } => (TType::Dispute, *value),
+                Transaction::Resolve { value, .. } => (TType::Resolve, *value),
+                Transaction::Chargeback { value, .. } => (TType::Chargeback, *value),
+                Transaction::Representment { .. } => (TType::Representment, None),
+            };
+            Self {
+                kind,
+                client: tx.client(),
+                tx: tx.tx_id(),
+                amount,
+            }
+        }
+    }
 }
 
 #[doc(hidden)]
@@ -118,9 +214,45 @@ pub mod tests {
     use super::*;
     use quickcheck::{Arbitrary, Gen};
 
+    fn parse(csv: &str) -> Vec<Result<Transaction, std::io::Error>> {
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(csv.as_bytes())
+            .into_deserialize::<serde::TransactionCompatCsv>()
+            .map(|tx| Transaction::try_from(tx.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_partial_amounts() {
+        let txs = parse("type,client,tx,amount\ndispute,1,2,0.5\nresolve,1,2\nchargeback,1,2,-1\n");
+        assert_eq!(
+            txs[0].as_ref().unwrap(),
+            &Transaction::Dispute {
+                client: 1,
+                tx_id: 2,
+                value: Some(Value::new(5, 1)),
+            }
+        );
+        assert_eq!(
+            txs[1].as_ref().unwrap(),
+            &Transaction::Resolve {
+                client: 1,
+                tx_id: 2,
+                value: None,
+            }
+        );
+        assert!(txs[2].is_err());
+    }
+
+    fn partial(g: &mut Gen) -> Option<Value> {
+        bool::arbitrary(g).then(|| Value::new(i64::arbitrary(g), u32::arbitrary(g) % 28))
+    }
+
     impl Arbitrary for Transaction {
         fn arbitrary(g: &mut Gen) -> Self {
-            match u32::arbitrary(g) % 5 {
+            match u32::arbitrary(g) % 6 {
                 0 => Self::Deposit {
                     client: u16::arbitrary(g),
                     value: Value::new(i64::arbitrary(g), u32::arbitrary(g) % 28),
@@ -134,14 +266,21 @@ pub mod tests {
                 2 => Self::Dispute {
                     client: u16::arbitrary(g),
                     tx_id: u32::arbitrary(g),
+                    value: partial(g),
                 },
                 3 => Self::Resolve {
                     client: u16::arbitrary(g),
                     tx_id: u32::arbitrary(g),
+                    value: partial(g),
                 },
                 4 => Self::Chargeback {
                     client: u16::arbitrary(g),
                     tx_id: u32::arbitrary(g),
+                    value: partial(g),
+                },
+                5 => Self::Representment {
+                    client: u16::arbitrary(g),
+                    tx_id: u32::arbitrary(g),
                 },
                 _ => unreachable!(),
             }